@@ -1,24 +1,121 @@
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
-use crate::user::User;
-use bcrypt::{hash, verify, DEFAULT_COST};
+use crate::user::{Role, User, UserPrefs};
+use crate::password::{self, PasswordHasher};
 use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+
+/// Wrong `/verify` codes allowed before the current code stops working.
+const MAX_VERIFICATION_ATTEMPTS: u32 = 5;
+/// How long an account must wait after a code was issued before asking for another.
+const VERIFICATION_RESEND_COOLDOWN: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct UserDatabase {
     users: HashMap<String, String>,
+    #[serde(default)]
+    prefs: HashMap<String, UserPrefs>,
+    #[serde(default)]
+    roles: HashMap<String, Role>,
+    #[serde(default)]
+    created_at: HashMap<String, u64>,
+    /// Accounts registered before logins were counted have no entry.
+    #[serde(default)]
+    login_counts: HashMap<String, u64>,
+    #[serde(default)]
+    emails: HashMap<String, StoredEmail>,
+    /// Each account's `canonical_username`, the key that must be unique
+    /// across accounts. Files from before it was stored are backfilled on load.
+    #[serde(default)]
+    canonical: HashMap<String, String>,
+    /// Accounts that haven't entered their emailed code yet, and that code.
+    /// An empty code was invalidated after too many wrong guesses.
+    #[serde(default)]
+    pending_verification: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredEmail {
+    /// The address, or its password-style hash when `hashed`.
+    value: String,
+    #[serde(default)]
+    hashed: bool,
+}
+
+/// Email collected at registration.
+pub struct RegistrationEmail<'a> {
+    pub address: &'a str,
+    /// Store only a hash of the address.
+    pub hashed: bool,
+    /// Code the account must `/verify` before it counts as verified.
+    pub verification_code: Option<String>,
+}
+
+/// Everything stored about an account except its password hash.
+#[derive(Debug, Serialize)]
+pub struct AccountInfo {
+    pub username: String,
+    pub role: Role,
+    pub prefs: UserPrefs,
+    pub created_at: Option<u64>,
+    pub login_count: u64,
+}
+
+/// Operator-configured username patterns, matched case-insensitively
+/// against the whole name; `*` matches any run of characters and `?` any
+/// single one.
+pub struct UsernameRules {
+    /// Refused at registration; existing accounts keep working.
+    reserved: Vec<Regex>,
+    /// Refused at registration and login.
+    banned: Vec<Regex>,
+}
+
+impl UsernameRules {
+    pub fn new(reserved: &[String], banned: &[String]) -> Self {
+        UsernameRules {
+            reserved: reserved.iter().filter_map(|p| pattern_regex(p)).collect(),
+            banned: banned.iter().filter_map(|p| pattern_regex(p)).collect(),
+        }
+    }
+
+    fn is_reserved(&self, username: &str) -> bool {
+        self.reserved.iter().any(|re| re.is_match(username))
+    }
+
+    fn is_banned(&self, username: &str) -> bool {
+        self.banned.iter().any(|re| re.is_match(username))
+    }
+}
+
+fn pattern_regex(pattern: &str) -> Option<Regex> {
+    let body = regex::escape(pattern).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("(?i)^{}$", body))
+        .inspect_err(|e| eprintln!("Ignoring username pattern {:?}: {}", pattern, e))
+        .ok()
 }
 
 pub struct AuthManager {
     file_path: String,
     database: UserDatabase,
+    hasher: Box<dyn PasswordHasher + Send>,
+    max_accounts: Option<usize>,
+    username_rules: UsernameRules,
+    /// Wrong guesses against each account's current verification code.
+    failed_verifications: HashMap<String, u32>,
+    /// When each account's current verification code was issued.
+    codes_issued: HashMap<String, Instant>,
 }
 
 impl AuthManager {
-    pub fn new(file_path: &str) -> Self {
-        let database = if Path::new(file_path).exists() {
+    /// `hasher` is used for new passwords; existing hashes of either
+    /// supported algorithm keep verifying. `max_accounts` caps registrations
+    /// and `username_rules` limits which names can register or log in.
+    pub fn new(file_path: &str, hasher: Box<dyn PasswordHasher + Send>, max_accounts: Option<usize>, username_rules: UsernameRules) -> Self {
+        let mut database: UserDatabase = if Path::new(file_path).exists() {
             let content = fs::read_to_string(file_path)
                 .expect("Failed to read user database");
             serde_json::from_str(&content)
@@ -26,25 +123,71 @@ impl AuthManager {
         } else {
             UserDatabase::default()
         };
+        for username in database.users.keys() {
+            database.canonical.entry(username.clone())
+                .or_insert_with(|| canonical_username(username));
+        }
 
         AuthManager {
             file_path: file_path.to_string(),
             database,
+            hasher,
+            max_accounts,
+            username_rules,
+            failed_verifications: HashMap::new(),
+            codes_issued: HashMap::new(),
         }
     }
 
-    pub fn register(&mut self, username: &str, password: &str) -> Result<User, String> {
+    pub fn register(&mut self, username: &str, password: &str, email: Option<RegistrationEmail>) -> Result<User, String> {
         self.validate_username(username)?;
         self.validate_password(password)?;
 
+        if self.username_rules.is_reserved(username) {
+            return Err("Username is reserved".to_string());
+        }
+
         if self.database.users.contains_key(username) {
             return Err("Username already exists".to_string());
         }
 
-        let hashed_password = hash(password, DEFAULT_COST)
-            .map_err(|_| "Failed to hash password".to_string())?;
+        if self.max_accounts.is_some_and(|max| self.database.users.len() >= max) {
+            return Err("Server has reached its account limit".to_string());
+        }
+
+        let canonical = canonical_username(username);
+        if self.database.canonical.values().any(|existing| *existing == canonical) {
+            return Err("Username is too similar to an existing account".to_string());
+        }
+        if self.database.prefs.values()
+            .filter_map(|prefs| prefs.display_name.as_deref())
+            .any(|display| canonical_username(display) == canonical) {
+            return Err("Username is too similar to an existing display name".to_string());
+        }
+
+        let hashed_password = self.hasher.hash(password)?;
         
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
         self.database.users.insert(username.to_string(), hashed_password);
+        self.database.canonical.insert(username.to_string(), canonical);
+        self.database.created_at.insert(username.to_string(), now);
+        self.database.login_counts.insert(username.to_string(), 0);
+        if let Some(email) = email {
+            let value = if email.hashed {
+                self.hasher.hash(email.address)?
+            } else {
+                email.address.to_string()
+            };
+            self.database.emails.insert(username.to_string(), StoredEmail { value, hashed: email.hashed });
+            if let Some(code) = email.verification_code {
+                self.database.pending_verification.insert(username.to_string(), code);
+                self.codes_issued.insert(username.to_string(), Instant::now());
+            }
+        }
         self.save_database()?;
 
         Ok(User::new(username.to_string()))
@@ -56,8 +199,7 @@ impl AuthManager {
         
         match self.database.users.get(username) {
             Some(stored_hash) => {
-                if verify(password, stored_hash)
-                    .map_err(|_| "Password verification failed".to_string())? {
+                if password::verify_password(password, stored_hash)? {
                     Ok(User::new(username.to_string()))
                 } else {
                     Err("Invalid password".to_string())
@@ -67,6 +209,191 @@ impl AuthManager {
         }
     }
 
+    /// Deletes an account after checking its password. The last admin
+    /// account cannot be deleted so the server is never left without one.
+    pub fn delete_user(&mut self, username: &str, password: &str) -> Result<(), String> {
+        let stored_hash = self.database.users.get(username)
+            .ok_or_else(|| "Username not found".to_string())?;
+
+        if !password::verify_password(password, stored_hash)? {
+            return Err("Invalid password".to_string());
+        }
+
+        if self.get_role(username) == Role::Admin
+            && self.database.roles.iter()
+                .filter(|(name, role)| **role == Role::Admin && self.database.users.contains_key(*name))
+                .count() <= 1 {
+            return Err("Cannot delete the last admin account".to_string());
+        }
+
+        let backup = (
+            self.database.users.remove(username),
+            self.database.prefs.remove(username),
+            self.database.roles.remove(username),
+            self.database.created_at.remove(username),
+            self.database.login_counts.remove(username),
+            self.database.emails.remove(username),
+            self.database.pending_verification.remove(username),
+            self.database.canonical.remove(username),
+        );
+
+        if let Err(e) = self.save_database() {
+            // Restore the account so memory matches the file on disk
+            let (hash, prefs, role, created_at, login_count, email, pending, canonical) = backup;
+            let name = username.to_string();
+            hash.map(|v| self.database.users.insert(name.clone(), v));
+            prefs.map(|v| self.database.prefs.insert(name.clone(), v));
+            role.map(|v| self.database.roles.insert(name.clone(), v));
+            created_at.map(|v| self.database.created_at.insert(name.clone(), v));
+            login_count.map(|v| self.database.login_counts.insert(name.clone(), v));
+            email.map(|v| self.database.emails.insert(name.clone(), v));
+            pending.map(|v| self.database.pending_verification.insert(name.clone(), v));
+            canonical.map(|v| self.database.canonical.insert(name, v));
+            return Err(e);
+        }
+
+        self.failed_verifications.remove(username);
+        self.codes_issued.remove(username);
+        Ok(())
+    }
+
+    pub fn account_info(&self, username: &str) -> Option<AccountInfo> {
+        if !self.database.users.contains_key(username) {
+            return None;
+        }
+
+        Some(AccountInfo {
+            username: username.to_string(),
+            role: self.get_role(username),
+            prefs: self.get_prefs(username),
+            created_at: self.database.created_at.get(username).copied(),
+            login_count: self.database.login_counts.get(username).copied().unwrap_or_default(),
+        })
+    }
+
+    /// Counts a successful login, returning whether it was the account's
+    /// first. Accounts older than login counting are never treated as new.
+    pub fn record_login(&mut self, username: &str) -> Result<bool, String> {
+        let previous = self.database.login_counts.get(username).copied();
+        self.database.login_counts.insert(username.to_string(), previous.unwrap_or_default() + 1);
+
+        self.save_database().inspect_err(|_| {
+            match previous {
+                Some(count) => self.database.login_counts.insert(username.to_string(), count),
+                None => self.database.login_counts.remove(username),
+            };
+        })?;
+        Ok(previous == Some(0))
+    }
+
+    /// False only while the account has an emailed code it hasn't entered.
+    pub fn is_verified(&self, username: &str) -> bool {
+        !self.database.pending_verification.contains_key(username)
+    }
+
+    /// Checks `code` against the account's pending code. After
+    /// `MAX_VERIFICATION_ATTEMPTS` wrong guesses the code is invalidated and
+    /// only a freshly issued one can verify the account.
+    pub fn verify_email(&mut self, username: &str, code: &str) -> Result<(), String> {
+        let invalidated = "Too many wrong codes; request a new one with /verify resend".to_string();
+        match self.database.pending_verification.get(username) {
+            None => return Err("Your account is already verified".to_string()),
+            Some(expected) if expected.is_empty() => return Err(invalidated),
+            Some(expected) if !password::constant_time_eq(expected.as_bytes(), code.as_bytes()) => {
+                let failures = self.failed_verifications.entry(username.to_string()).or_default();
+                *failures += 1;
+                if *failures < MAX_VERIFICATION_ATTEMPTS {
+                    return Err(format!("Invalid verification code; attempts left: {}", MAX_VERIFICATION_ATTEMPTS - *failures));
+                }
+                self.failed_verifications.remove(username);
+                self.set_pending_code(username, String::new())?;
+                return Err(invalidated);
+            }
+            Some(_) => {}
+        }
+
+        let previous = self.database.pending_verification.remove(username);
+        self.save_database().inspect_err(|_| {
+            previous.map(|code| self.database.pending_verification.insert(username.to_string(), code));
+        })?;
+        self.failed_verifications.remove(username);
+        self.codes_issued.remove(username);
+        Ok(())
+    }
+
+    /// The account's address, unless none was collected or only its hash is stored.
+    pub fn plain_email(&self, username: &str) -> Option<String> {
+        self.database.emails.get(username)
+            .filter(|email| !email.hashed)
+            .map(|email| email.value.clone())
+    }
+
+    /// Replaces the account's pending code with a newly issued one, which
+    /// gets a fresh set of attempts.
+    pub fn set_verification_code(&mut self, username: &str, code: String) -> Result<(), String> {
+        self.set_pending_code(username, code)?;
+        self.failed_verifications.remove(username);
+        self.codes_issued.insert(username.to_string(), Instant::now());
+        Ok(())
+    }
+
+    /// How long until the account may be sent another code, if it must wait.
+    pub fn resend_wait(&self, username: &str) -> Option<Duration> {
+        self.codes_issued.get(username)
+            .and_then(|issued| VERIFICATION_RESEND_COOLDOWN.checked_sub(issued.elapsed()))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    fn set_pending_code(&mut self, username: &str, code: String) -> Result<(), String> {
+        let previous = self.database.pending_verification.insert(username.to_string(), code);
+        self.save_database().inspect_err(|_| {
+            match previous {
+                Some(code) => self.database.pending_verification.insert(username.to_string(), code),
+                None => self.database.pending_verification.remove(username),
+            };
+        })
+    }
+
+    pub fn get_role(&self, username: &str) -> Role {
+        self.database.roles.get(username).copied().unwrap_or_default()
+    }
+
+    /// Lists registered moderators and admins, admins first.
+    pub fn staff(&self) -> Vec<(String, Role)> {
+        let mut staff: Vec<(String, Role)> = self.database.roles.iter()
+            .filter(|(name, role)| role.is_staff() && self.database.users.contains_key(*name))
+            .map(|(name, role)| (name.clone(), *role))
+            .collect();
+        staff.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        staff
+    }
+
+    pub fn get_prefs(&self, username: &str) -> UserPrefs {
+        self.database.prefs.get(username).cloned().unwrap_or_default()
+    }
+
+    /// Whether `name` collides with another account's login or display name,
+    /// compared by `canonical_username`.
+    pub fn display_name_taken(&self, name: &str, owner: &str) -> bool {
+        let canonical = canonical_username(name);
+        self.database.canonical.iter()
+            .filter(|(username, _)| *username != owner)
+            .any(|(_, key)| *key == canonical)
+            || self.database.prefs.iter()
+                .filter(|(username, _)| *username != owner)
+                .filter_map(|(_, prefs)| prefs.display_name.as_deref())
+                .any(|display| canonical_username(display) == canonical)
+    }
+
+    pub fn set_prefs(&mut self, username: &str, prefs: UserPrefs) -> Result<(), String> {
+        if !self.database.users.contains_key(username) {
+            return Err("Username not found".to_string());
+        }
+
+        self.database.prefs.insert(username.to_string(), prefs);
+        self.save_database()
+    }
+
     fn save_database(&self) -> Result<(), String> {
         let json = serde_json::to_string_pretty(&self.database)
             .map_err(|e| format!("Failed to serialize database: {}", e))?;
@@ -99,6 +426,10 @@ impl AuthManager {
         if !username_regex.is_match(username) {
             return Err("Username can only contain letters, numbers, underscores, and hyphens".to_string());
         }
+
+        if self.username_rules.is_banned(username) {
+            return Err("Username is not allowed".to_string());
+        }
         
         Ok(())
     }
@@ -119,3 +450,221 @@ impl AuthManager {
         Ok(())
     }
 }
+
+/// Reduces a name to the form used for uniqueness checks: NFKC-normalized,
+/// case-folded and with lookalike characters folded together, so `Alice`,
+/// `alice`, `aIice` and `аlice` (Cyrillic `а`) all collide while the account
+/// keeps its display form. Capital `I` looks like `l` but folds to `i`, so
+/// `i`, `l` and `1` are all treated as one letter.
+fn canonical_username(username: &str) -> String {
+    username.nfkc()
+        .flat_map(char::to_lowercase)
+        .map(confusable_skeleton)
+        .collect()
+}
+
+/// Maps common Cyrillic and Greek lookalikes, and digits that pass for
+/// letters, to the Latin letter they are mistaken for.
+fn confusable_skeleton(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'е' | 'ε' => 'e',
+        'һ' => 'h',
+        'i' | 'і' | 'ι' | 'l' | '1' => 'l',
+        'ј' => 'j',
+        'κ' => 'k',
+        'ո' => 'n',
+        'о' | 'ο' | '0' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ѕ' => 's',
+        'υ' => 'u',
+        'ν' => 'v',
+        'ԝ' => 'w',
+        'х' | 'χ' => 'x',
+        'у' => 'y',
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bcrypt at its lowest cost, so tests don't pay for the real one.
+    struct FastHasher;
+
+    impl PasswordHasher for FastHasher {
+        fn hash(&self, password: &str) -> Result<String, String> {
+            bcrypt::hash(password, 4).map_err(|e| e.to_string())
+        }
+
+        fn verify(&self, password: &str, hash: &str) -> Result<bool, String> {
+            bcrypt::verify(password, hash).map_err(|e| e.to_string())
+        }
+    }
+
+    /// A users file holding `username` with the password `password1`.
+    fn users_file(file: &TempFile, username: &str) {
+        let hash = FastHasher.hash("password1").unwrap();
+        fs::write(&file.0, serde_json::json!({"users": {username: hash}}).to_string()).unwrap();
+    }
+
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("chatserver-auth-{}.json", uuid::Uuid::new_v4()));
+            TempFile(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn manager(file: &TempFile) -> AuthManager {
+        AuthManager::new(&file.0, Box::new(FastHasher), None, UsernameRules::new(&[], &[]))
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn canonical_username_folds_case() {
+        assert_eq!(canonical_username("Alice"), canonical_username("alice"));
+        assert_eq!(canonical_username("ALICE"), canonical_username("alice"));
+    }
+
+    #[test]
+    fn canonical_username_folds_homoglyphs() {
+        // Cyrillic а and о, Greek ο, capital I for l, zero for o
+        let alice = canonical_username("alice");
+        assert_eq!(canonical_username("аlice"), alice);
+        assert_eq!(canonical_username("aIice"), alice);
+        assert_eq!(canonical_username("a1ice"), alice);
+        assert_eq!(canonical_username("bοb"), "bob");
+        assert_eq!(canonical_username("b0b"), "bob");
+        // Fullwidth forms are unified by NFKC
+        assert_eq!(canonical_username("ａｌｉｃｅ"), alice);
+        assert_ne!(canonical_username("alice"), canonical_username("alicia"));
+    }
+
+    #[test]
+    fn register_rejects_case_and_lookalike_collisions() {
+        let file = TempFile::new();
+        let mut auth = manager(&file);
+        auth.register("alice", "password1", None).unwrap();
+
+        for name in ["Alice", "ALICE", "aIice", "a1ice"] {
+            assert_eq!(auth.register(name, "password1", None).unwrap_err(),
+                       "Username is too similar to an existing account", "{}", name);
+        }
+        assert!(auth.register("alicia", "password1", None).is_ok());
+    }
+
+    #[test]
+    fn register_rejects_names_matching_a_display_name() {
+        let file = TempFile::new();
+        let mut auth = manager(&file);
+        auth.register("alice", "password1", None).unwrap();
+        let prefs = UserPrefs { display_name: Some("Zelda".to_string()), ..Default::default() };
+        auth.set_prefs("alice", prefs).unwrap();
+
+        for name in ["zelda", "ZELDA", "ze1da"] {
+            assert_eq!(auth.register(name, "password1", None).unwrap_err(),
+                       "Username is too similar to an existing display name", "{}", name);
+        }
+        assert!(auth.register("zeldas", "password1", None).is_ok());
+    }
+
+    #[test]
+    fn display_names_cannot_impersonate_accounts() {
+        let file = TempFile::new();
+        let mut auth = manager(&file);
+        auth.register("alice", "password1", None).unwrap();
+        auth.register("mallory", "password1", None).unwrap();
+
+        assert!(auth.display_name_taken("аlice", "mallory"));
+        assert!(auth.display_name_taken("ALICE", "mallory"));
+        assert!(!auth.display_name_taken("alice", "alice"));
+        assert!(!auth.display_name_taken("bob", "mallory"));
+    }
+
+    #[test]
+    fn canonical_keys_are_persisted_and_backfilled() {
+        let file = TempFile::new();
+        manager(&file).register("Alice", "password1", None).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(&file.0).unwrap()).unwrap();
+        assert_eq!(saved["canonical"]["Alice"], canonical_username("alice"));
+
+        // A file written before canonical keys existed still collides
+        users_file(&file, "Bob");
+        let mut auth = manager(&file);
+        assert!(auth.register("b0b", "password1", None).is_err());
+    }
+
+    #[test]
+    fn reserved_names_cannot_register_but_existing_accounts_log_in() {
+        let file = TempFile::new();
+        users_file(&file, "admin");
+        let rules = UsernameRules::new(&patterns(&["admin", "mod*"]), &[]);
+        let mut auth = AuthManager::new(&file.0, Box::new(FastHasher), None, rules);
+
+        assert_eq!(auth.register("Moderator", "password1", None).unwrap_err(), "Username is reserved");
+        assert_eq!(auth.register("mod", "password1", None).unwrap_err(), "Username is reserved");
+        assert!(auth.register("modest_name", "password1", None).is_err());
+        assert!(auth.register("amod", "password1", None).is_ok());
+        assert!(auth.login("admin", "password1").is_ok());
+    }
+
+    #[test]
+    fn banned_names_can_neither_register_nor_log_in() {
+        let file = TempFile::new();
+        users_file(&file, "spammer1");
+        let rules = UsernameRules::new(&[], &patterns(&["spammer?", "*bot"]));
+        let mut auth = AuthManager::new(&file.0, Box::new(FastHasher), None, rules);
+
+        assert_eq!(auth.login("spammer1", "password1").unwrap_err(), "Username is not allowed");
+        assert_eq!(auth.register("ChatBot", "password1", None).unwrap_err(), "Username is not allowed");
+        assert!(auth.register("spammer12", "password1", None).is_ok());
+    }
+
+    #[test]
+    fn registrations_stop_at_the_account_cap() {
+        let file = TempFile::new();
+        let mut auth = AuthManager::new(&file.0, Box::new(FastHasher), Some(2), UsernameRules::new(&[], &[]));
+        auth.register("alice", "password1", None).unwrap();
+        auth.register("bob", "password1", None).unwrap();
+        assert_eq!(auth.register("carol", "password1", None).unwrap_err(), "Server has reached its account limit");
+
+        auth.delete_user("bob", "password1").unwrap();
+        assert!(auth.register("carol", "password1", None).is_ok());
+    }
+
+    #[test]
+    fn verification_code_is_invalidated_after_repeated_misses() {
+        let file = TempFile::new();
+        let mut auth = manager(&file);
+        let email = RegistrationEmail { address: "a@example.com", hashed: false, verification_code: Some("123456".to_string()) };
+        auth.register("alice", "password1", Some(email)).unwrap();
+        assert!(!auth.is_verified("alice"));
+        assert!(auth.resend_wait("alice").is_some());
+
+        for _ in 1..MAX_VERIFICATION_ATTEMPTS {
+            assert!(auth.verify_email("alice", "000000").unwrap_err().starts_with("Invalid verification code"));
+        }
+        assert!(auth.verify_email("alice", "000000").unwrap_err().starts_with("Too many wrong codes"));
+        // Even the right code no longer works
+        assert!(auth.verify_email("alice", "123456").is_err());
+
+        auth.set_verification_code("alice", "654321".to_string()).unwrap();
+        auth.verify_email("alice", "654321").unwrap();
+        assert!(auth.is_verified("alice"));
+    }
+}
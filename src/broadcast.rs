@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::user::Role;
+
+/// How long an identical line in the same channel counts as a repeat.
+pub const COALESCE_WINDOW: Duration = Duration::from_secs(2);
+/// Identical lines delivered per channel within `COALESCE_WINDOW` before
+/// further copies are dropped, so a few deliberate `+1`s still go through.
+pub const MAX_REPEATS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Send,
+    /// The same line was just sent to this channel too many times.
+    Repeated,
+    /// The server-wide delivery budget is spent for now.
+    OverBudget,
+}
+
+struct RecentLine {
+    text: String,
+    first_seen: Instant,
+    count: u32,
+}
+
+/// Caps the total broadcast work the server does per second. Every chat
+/// line costs one delivery per recipient, drawn from a token bucket that
+/// refills at `budget_per_sec` and holds at most one second's worth, so a
+/// burst in a large channel can't monopolise handler threads. Repeats of
+/// the same line in a channel are coalesced before they cost anything.
+pub struct BroadcastLimiter {
+    budget_per_sec: u64,
+    tokens: f64,
+    last_refill: Instant,
+    recent: HashMap<String, RecentLine>,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl BroadcastLimiter {
+    /// A `budget_per_sec` of 0 disables the budget; repeats are still coalesced.
+    pub fn new(budget_per_sec: u64) -> Self {
+        BroadcastLimiter {
+            budget_per_sec,
+            tokens: budget_per_sec as f64,
+            last_refill: Instant::now(),
+            recent: HashMap::new(),
+            delivered: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Decides whether `text` may go out to `recipients` members of `channel`,
+    /// charging the budget if it may.
+    pub fn admit(&mut self, channel: &str, text: &str, recipients: usize) -> Admission {
+        let now = Instant::now();
+        self.recent.retain(|_, line| now.duration_since(line.first_seen) < COALESCE_WINDOW);
+
+        if let Some(line) = self.recent.get(channel)
+            && line.text == text
+            && line.count >= MAX_REPEATS {
+            self.dropped += 1;
+            return Admission::Repeated;
+        }
+
+        if self.budget_per_sec > 0 {
+            let capacity = self.budget_per_sec as f64;
+            let refill = now.duration_since(self.last_refill).as_secs_f64() * capacity;
+            self.tokens = (self.tokens + refill).min(capacity);
+            self.last_refill = now;
+
+            // A single line is always allowed when the bucket is full, even to a
+            // channel larger than the whole budget
+            let cost = (recipients as f64).min(capacity);
+            if self.tokens < cost {
+                self.dropped += 1;
+                return Admission::OverBudget;
+            }
+            self.tokens -= cost;
+        }
+
+        match self.recent.get_mut(channel) {
+            Some(line) if line.text == text => line.count += 1,
+            _ => {
+                self.recent.insert(channel.to_string(), RecentLine {
+                    text: text.to_string(),
+                    first_seen: now,
+                    count: 1,
+                });
+            }
+        }
+        self.delivered += recipients as u64;
+        Admission::Send
+    }
+
+    /// Deliveries made and chat lines dropped since startup.
+    pub fn totals(&self) -> (u64, u64) {
+        (self.delivered, self.dropped)
+    }
+}
+
+/// How fast one user may chat: up to `burst` lines at once, refilled at
+/// `messages_per_minute`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub messages_per_minute: u32,
+    pub burst: u32,
+}
+
+/// Per-user flood control with a token bucket per sender, sized by the
+/// limit configured for the sender's role. Roles without a limit are not
+/// throttled. Buckets are shared by all of a user's connections.
+pub struct SenderLimiter {
+    limits: HashMap<Role, RateLimit>,
+    buckets: HashMap<String, SenderBucket>,
+}
+
+struct SenderBucket {
+    tokens: f64,
+    last_refill: Instant,
+    /// When the bucket will be full again, after which it can be forgotten.
+    full_at: Instant,
+}
+
+impl SenderLimiter {
+    pub fn new(limits: HashMap<Role, RateLimit>) -> Self {
+        SenderLimiter {
+            limits,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Charges one line to `username`, or returns how long until they may send again.
+    pub fn admit(&mut self, username: &str, role: Role) -> Result<(), Duration> {
+        let Some(limit) = self.limits.get(&role).filter(|limit| limit.messages_per_minute > 0) else {
+            return Ok(());
+        };
+        let capacity = limit.burst.max(1) as f64;
+        let per_sec = limit.messages_per_minute as f64 / 60.0;
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| bucket.full_at > now);
+
+        let bucket = self.buckets.entry(username.to_string()).or_insert(SenderBucket {
+            tokens: capacity,
+            last_refill: now,
+            full_at: now,
+        });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * per_sec).min(capacity);
+        bucket.last_refill = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec));
+        }
+        bucket.tokens -= 1.0;
+        bucket.full_at = now + Duration::from_secs_f64((capacity - bucket.tokens) / per_sec);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_coalesced_per_channel() {
+        let mut limiter = BroadcastLimiter::new(0);
+        for _ in 0..MAX_REPEATS {
+            assert_eq!(limiter.admit("general", "+1", 5), Admission::Send);
+        }
+        assert_eq!(limiter.admit("general", "+1", 5), Admission::Repeated);
+        assert_eq!(limiter.admit("random", "+1", 5), Admission::Send);
+        assert_eq!(limiter.admit("general", "something else", 5), Admission::Send);
+        assert_eq!(limiter.totals(), (25, 1));
+    }
+
+    #[test]
+    fn budget_limits_deliveries() {
+        let mut limiter = BroadcastLimiter::new(100);
+        assert_eq!(limiter.admit("general", "one", 60), Admission::Send);
+        assert_eq!(limiter.admit("general", "two", 60), Admission::OverBudget);
+        assert_eq!(limiter.admit("small", "three", 30), Admission::Send);
+    }
+
+    #[test]
+    fn a_full_bucket_admits_a_channel_larger_than_the_budget() {
+        let mut limiter = BroadcastLimiter::new(10);
+        assert_eq!(limiter.admit("huge", "hello", 1000), Admission::Send);
+        assert_eq!(limiter.admit("huge", "again", 1), Admission::OverBudget);
+    }
+
+    fn limits() -> HashMap<Role, RateLimit> {
+        HashMap::from([
+            (Role::User, RateLimit { messages_per_minute: 60, burst: 2 }),
+            (Role::Moderator, RateLimit { messages_per_minute: 600, burst: 10 }),
+        ])
+    }
+
+    #[test]
+    fn sender_limit_allows_a_burst_then_throttles() {
+        let mut limiter = SenderLimiter::new(limits());
+        assert!(limiter.admit("bob", Role::User).is_ok());
+        assert!(limiter.admit("bob", Role::User).is_ok());
+        let wait = limiter.admit("bob", Role::User).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+        // Buckets are per user
+        assert!(limiter.admit("carol", Role::User).is_ok());
+    }
+
+    #[test]
+    fn sender_limit_depends_on_role() {
+        let mut limiter = SenderLimiter::new(limits());
+        for _ in 0..10 {
+            assert!(limiter.admit("mod", Role::Moderator).is_ok());
+        }
+        assert!(limiter.admit("mod", Role::Moderator).is_err());
+        // Admins have no configured limit
+        for _ in 0..100 {
+            assert!(limiter.admit("root", Role::Admin).is_ok());
+        }
+    }
+
+    #[test]
+    fn sender_limit_refills_over_time() {
+        let mut limiter = SenderLimiter::new(HashMap::from([
+            (Role::User, RateLimit { messages_per_minute: 6000, burst: 1 }),
+        ]));
+        assert!(limiter.admit("bob", Role::User).is_ok());
+        assert!(limiter.admit("bob", Role::User).is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.admit("bob", Role::User).is_ok());
+    }
+}
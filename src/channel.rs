@@ -1,18 +1,115 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ChannelType {
+    #[serde(alias = "text")]
     Text,
+    #[serde(alias = "voice")]
     Voice,
 }
 
+/// A channel created on first run, when there is no channels file yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StarterChannel {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub channel_type: ChannelType,
+    /// Join greeting, which also serves as the channel's topic.
+    #[serde(default)]
+    pub welcome: Option<String>,
+}
+
+/// A channel member and when they joined, as Unix seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "MemberRecord")]
+pub struct Member {
+    pub name: String,
+    pub joined_at: u64,
+}
+
+/// Older channel files list members as plain names; those load with an unknown (zero) join time.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MemberRecord {
+    Name(String),
+    Full { name: String, joined_at: u64 },
+}
+
+impl From<MemberRecord> for Member {
+    fn from(record: MemberRecord) -> Self {
+        match record {
+            MemberRecord::Name(name) => Member { name, joined_at: 0 },
+            MemberRecord::Full { name, joined_at } => Member { name, joined_at },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Channel {
     pub name: String,
     pub channel_type: ChannelType,
-    pub users: Vec<String>,
+    pub users: Vec<Member>,
+    #[serde(default = "default_logging")]
+    pub logging: bool,
+    /// Greeting sent privately to each user who joins the channel.
+    #[serde(default)]
+    pub welcome: Option<String>,
+    /// Rejects new joins; members already inside stay.
+    #[serde(default)]
+    pub locked: bool,
+    /// Read-only for everyone but moderators and admins.
+    #[serde(default)]
+    pub announcement: bool,
+    /// Most voice sessions a voice channel accepts at once.
+    #[serde(default = "default_max_participants")]
+    pub max_participants: usize,
+    /// Left out of listings and refused to new joiners; removed once empty.
+    #[serde(skip)]
+    pub hidden: bool,
+    /// Whether the channel is written to the channels file.
+    #[serde(skip, default = "default_persistent")]
+    pub persistent: bool,
+    /// Connections receiving the channel's messages without being members.
+    #[serde(skip)]
+    pub observers: Vec<Uuid>,
+    /// Arrival times of chat messages within the last `RATE_WINDOW`.
+    #[serde(skip)]
+    pub recent_messages: VecDeque<Instant>,
+    /// Minimum gap between one user's messages while slow mode is on.
+    #[serde(skip)]
+    pub slow_mode: Option<Duration>,
+    #[serde(skip)]
+    last_message_by: HashMap<String, Instant>,
+    /// Chat messages delivered per user since startup.
+    #[serde(skip)]
+    message_counts: HashMap<String, u64>,
+    /// Latest chat line and when it was sent, kept only while the channel is logged.
+    #[serde(skip)]
+    pub last_message: Option<(String, Instant)>,
+}
+
+/// Window over which a channel's message rate is measured.
+pub const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Voice participant cap for channels that don't set their own.
+pub const DEFAULT_MAX_PARTICIPANTS: usize = 20;
+pub const MAX_PARTICIPANTS_LIMIT: usize = 100;
+
+fn default_logging() -> bool {
+    true
+}
+
+fn default_persistent() -> bool {
+    true
+}
+
+fn default_max_participants() -> usize {
+    DEFAULT_MAX_PARTICIPANTS
 }
 
 impl Channel {
@@ -21,100 +118,451 @@ impl Channel {
             name,
             channel_type,
             users: Vec::new(),
+            logging: true,
+            welcome: None,
+            locked: false,
+            announcement: false,
+            max_participants: DEFAULT_MAX_PARTICIPANTS,
+            hidden: false,
+            persistent: true,
+            observers: Vec::new(),
+            recent_messages: VecDeque::new(),
+            slow_mode: None,
+            last_message_by: HashMap::new(),
+            message_counts: HashMap::new(),
+            last_message: None,
         }
     }
+
+    pub fn has_member(&self, username: &str) -> bool {
+        self.users.iter().any(|member| member.name == username)
+    }
+
+    pub fn member_names(&self) -> Vec<String> {
+        self.users.iter().map(|member| member.name.clone()).collect()
+    }
+
+    /// Users by messages sent since startup, most first, ties by name.
+    pub fn message_counts(&self) -> Vec<(String, u64)> {
+        let mut counts: Vec<(String, u64)> = self.message_counts.iter()
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Chat messages received within the last `RATE_WINDOW`.
+    pub fn message_rate(&self) -> usize {
+        self.recent_messages.iter()
+            .filter(|t| t.elapsed() <= RATE_WINDOW)
+            .count()
+    }
 }
 
+/// Owns every channel, each behind its own lock so operations on different
+/// channels don't contend. Callers hold the manager in an `RwLock`: a read
+/// lock is enough for anything that touches existing channels, while adding
+/// channels needs the write lock. Never hold two channel locks at once.
 pub struct ChannelManager {
-    channels: HashMap<String, Channel>,
+    channels: HashMap<String, Mutex<Channel>>,
     config_file: String,
+    /// Serializes writes of the channels file, which may happen under a read
+    /// lock. Holds the file's mtime as of our last load or save, so a change
+    /// made by someone else in between can be detected before overwriting it.
+    save_lock: Mutex<Option<SystemTime>>,
+}
+
+/// A panic while a channel was locked can't leave it half-updated in a way
+/// that matters here, so keep using it instead of failing every later call.
+fn lock_channel(channel: &Mutex<Channel>) -> MutexGuard<'_, Channel> {
+    channel.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
 impl ChannelManager {
-    pub fn new() -> Self {
-        let mut manager = ChannelManager {
-            channels: HashMap::new(),
-            config_file: "channels.json".to_string(),
-        };
-        
-        manager.load_channels().unwrap_or_else(|e| {
-            eprintln!("Failed to load channels: {}", e);
-        });
-        
-        manager
-    }
-    
-    pub fn new_with_config(config_file: &str) -> Self {
+    /// Loads `config_file`, or creates it from `starter_channels` (the
+    /// built-in set when empty) if it doesn't exist yet.
+    pub fn new_with_config(config_file: &str, starter_channels: &[StarterChannel]) -> Self {
         let mut manager = ChannelManager {
             channels: HashMap::new(),
             config_file: config_file.to_string(),
+            save_lock: Mutex::new(None),
         };
         
-        manager.load_channels().unwrap_or_else(|e| {
+        manager.load_channels(starter_channels).unwrap_or_else(|e| {
             eprintln!("Failed to load channels: {}", e);
         });
         
         manager
     }
 
-    pub fn create_channel(&mut self, name: &str, channel_type: ChannelType) -> bool {
+    /// Creates and persists a channel. If saving fails the channel is removed
+    /// again so memory and disk stay consistent.
+    pub fn create_channel(&mut self, name: &str, channel_type: ChannelType) -> Result<(), String> {
         if self.channels.contains_key(name) {
-            return false;
+            return Err("Channel already exists".to_string());
         }
 
         self.channels.insert(
             name.to_string(),
-            Channel::new(name.to_string(), channel_type)
+            Mutex::new(Channel::new(name.to_string(), channel_type))
         );
-        
-        self.save_channels().unwrap_or_else(|e| {
-            eprintln!("Failed to save channels: {}", e);
-        });
-        
-        true
+
+        if let Err(e) = self.save_channels() {
+            self.channels.remove(name);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Removes a channel from memory and from the channels file. If saving
+    /// fails the channel is put back.
+    pub fn delete_channel(&mut self, name: &str) -> Result<(), String> {
+        let channel = self.channels.remove(name)
+            .ok_or_else(|| "Channel does not exist".to_string())?;
+
+        self.save_channels().inspect_err(|_| {
+            self.channels.insert(name.to_string(), channel);
+        })
+    }
+
+    /// Removes a hidden channel once nobody is in it or watching it,
+    /// returning whether it was removed.
+    pub fn remove_if_abandoned(&mut self, name: &str) -> bool {
+        let abandoned = self.get_channel(name)
+            .is_some_and(|ch| ch.hidden && ch.users.is_empty() && ch.observers.is_empty());
+        if abandoned {
+            self.channels.remove(name);
+        }
+        abandoned
+    }
+
+    /// Creates the channel if it is missing, e.g. a configured default channel
+    /// that is absent from the channels file.
+    pub fn ensure_channel(&mut self, name: &str, channel_type: ChannelType) {
+        if !self.channel_exists(name)
+            && let Err(e) = self.create_channel(name, channel_type) {
+            eprintln!("Failed to create channel {}: {}", name, e);
+        }
     }
 
     pub fn channel_exists(&self, name: &str) -> bool {
         self.channels.contains_key(name)
     }
 
-    pub fn get_channel(&self, name: &str) -> Option<&Channel> {
-        self.channels.get(name)
+    /// Locks and returns one channel. Drop the guard before locking another.
+    pub fn get_channel(&self, name: &str) -> Option<MutexGuard<'_, Channel>> {
+        self.channels.get(name).map(lock_channel)
     }
 
-    pub fn join_channel(&mut self, channel_name: &str, username: String) {
-        if let Some(channel) = self.channels.get_mut(channel_name) {
-            if !channel.users.contains(&username) {
-                channel.users.push(username);
+    pub fn join_channel(&self, channel_name: &str, username: String) {
+        if let Some(mut channel) = self.get_channel(channel_name)
+            && !channel.has_member(&username) {
+            let joined_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            channel.users.push(Member { name: username, joined_at });
+        }
+    }
+
+    pub fn leave_channel(&self, channel_name: &str, username: &str) {
+        if let Some(mut channel) = self.get_channel(channel_name) {
+            channel.users.retain(|member| member.name != username);
+        }
+    }
+
+    pub fn leave_all_channels(&self, username: &str) {
+        for channel in self.channels.values() {
+            lock_channel(channel).users.retain(|member| member.name != username);
+        }
+    }
+
+    /// Starts observing a channel, ending any other spectating session of the connection.
+    pub fn spectate(&self, channel_name: &str, client_id: Uuid) -> Result<(), String> {
+        if !self.channels.contains_key(channel_name) {
+            return Err("Channel does not exist".to_string());
+        }
+
+        self.unspectate(client_id);
+        if let Some(mut channel) = self.get_channel(channel_name) {
+            channel.observers.push(client_id);
+        }
+        Ok(())
+    }
+
+    /// Stops observing, returning the channel that was being observed.
+    pub fn unspectate(&self, client_id: Uuid) -> Option<String> {
+        self.channels.values().find_map(|channel| {
+            let mut channel = lock_channel(channel);
+            if !channel.observers.contains(&client_id) {
+                return None;
             }
+            channel.observers.retain(|id| *id != client_id);
+            Some(channel.name.clone())
+        })
+    }
+
+    pub fn spectated_channel(&self, client_id: Uuid) -> Option<String> {
+        self.channels.values()
+            .map(lock_channel)
+            .find(|ch| ch.observers.contains(&client_id))
+            .map(|ch| ch.name.clone())
+    }
+
+    /// How long `username` must still wait before speaking, if the channel is in slow mode.
+    pub fn slow_mode_wait(&self, channel_name: &str, username: &str) -> Option<Duration> {
+        let channel = self.get_channel(channel_name)?;
+        let delay = channel.slow_mode?;
+        let last = channel.last_message_by.get(username)?;
+        delay.checked_sub(last.elapsed()).filter(|wait| !wait.is_zero())
+    }
+
+    /// Counts a chat message that was actually delivered, for `/count`.
+    pub fn count_message(&self, channel_name: &str, username: &str) {
+        if let Some(mut channel) = self.get_channel(channel_name) {
+            *channel.message_counts.entry(username.to_string()).or_default() += 1;
         }
     }
 
-    pub fn leave_channel(&mut self, channel_name: &str, username: &str) {
-        if let Some(channel) = self.channels.get_mut(channel_name) {
-            channel.users.retain(|u| u != username);
+    /// Remembers `message` as the channel's latest line for previews, unless
+    /// the channel isn't logged.
+    pub fn record_last_message(&self, channel_name: &str, message: &str) {
+        if let Some(mut channel) = self.get_channel(channel_name)
+            && channel.logging {
+            channel.last_message = Some((message.to_string(), Instant::now()));
         }
     }
 
-    pub fn leave_all_channels(&mut self, username: &str) {
-        for channel in self.channels.values_mut() {
-            channel.users.retain(|u| u != username);
+    pub fn forget_last_message(&self, channel_name: &str) {
+        if let Some(mut channel) = self.get_channel(channel_name) {
+            channel.last_message = None;
         }
     }
 
-    pub fn list_channels(&self) -> Vec<(String, ChannelType, usize)> {
+    /// Records a chat message in the channel's rate window. With `auto_slow_mode`
+    /// set to `(threshold, delay)`, slow mode turns on once the window holds
+    /// `threshold` messages and off again when it drops below half that.
+    /// Returns the new slow-mode state when it changed.
+    pub fn record_message(&self, channel_name: &str, username: &str, auto_slow_mode: Option<(usize, Duration)>) -> Option<bool> {
+        let mut channel = self.get_channel(channel_name)?;
+        let now = Instant::now();
+
+        channel.recent_messages.push_back(now);
+        while channel.recent_messages.front().is_some_and(|t| now.duration_since(*t) > RATE_WINDOW) {
+            channel.recent_messages.pop_front();
+        }
+        channel.last_message_by.insert(username.to_string(), now);
+
+        let (threshold, delay) = auto_slow_mode?;
+        let rate = channel.recent_messages.len();
+        match channel.slow_mode {
+            None if rate >= threshold => {
+                channel.slow_mode = Some(delay);
+                Some(true)
+            }
+            Some(_) if rate < threshold / 2 => {
+                channel.slow_mode = None;
+                channel.last_message_by.clear();
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn channels_of(&self, username: &str) -> Vec<String> {
+        self.channels.values()
+            .map(lock_channel)
+            .filter(|ch| ch.has_member(username))
+            .map(|ch| ch.name.clone())
+            .collect()
+    }
+
+    pub fn set_logging(&self, channel_name: &str, enabled: bool) -> Result<(), String> {
+        let previous = {
+            let mut channel = self.get_channel(channel_name)
+                .ok_or_else(|| "Channel does not exist".to_string())?;
+            if !enabled {
+                channel.last_message = None;
+            }
+            std::mem::replace(&mut channel.logging, enabled)
+        };
+
+        self.save_channels().inspect_err(|_| {
+            if let Some(mut channel) = self.get_channel(channel_name) {
+                channel.logging = previous;
+            }
+        })
+    }
+
+    pub fn set_welcome(&self, channel_name: &str, welcome: Option<String>) -> Result<(), String> {
+        let previous = {
+            let mut channel = self.get_channel(channel_name)
+                .ok_or_else(|| "Channel does not exist".to_string())?;
+            std::mem::replace(&mut channel.welcome, welcome)
+        };
+
+        self.save_channels().inspect_err(|_| {
+            if let Some(mut channel) = self.get_channel(channel_name) {
+                channel.welcome = previous;
+            }
+        })
+    }
+
+    pub fn set_locked(&self, channel_name: &str, locked: bool) -> Result<(), String> {
+        let previous = {
+            let mut channel = self.get_channel(channel_name)
+                .ok_or_else(|| "Channel does not exist".to_string())?;
+            std::mem::replace(&mut channel.locked, locked)
+        };
+
+        self.save_channels().inspect_err(|_| {
+            if let Some(mut channel) = self.get_channel(channel_name) {
+                channel.locked = previous;
+            }
+        })
+    }
+
+    pub fn set_announcement(&self, channel_name: &str, announcement: bool) -> Result<(), String> {
+        let previous = {
+            let mut channel = self.get_channel(channel_name)
+                .ok_or_else(|| "Channel does not exist".to_string())?;
+            std::mem::replace(&mut channel.announcement, announcement)
+        };
+
+        self.save_channels().inspect_err(|_| {
+            if let Some(mut channel) = self.get_channel(channel_name) {
+                channel.announcement = previous;
+            }
+        })
+    }
+
+    /// Drops a channel from listings and the channels file while its
+    /// current members stay; `remove_if_abandoned` deletes it once empty.
+    pub fn hide_channel(&self, channel_name: &str) -> Result<(), String> {
+        let previous = {
+            let mut channel = self.get_channel(channel_name)
+                .ok_or_else(|| "Channel does not exist".to_string())?;
+            let previous = (channel.hidden, channel.persistent);
+            channel.hidden = true;
+            channel.persistent = false;
+            previous
+        };
+
+        self.save_channels().inspect_err(|_| {
+            if let Some(mut channel) = self.get_channel(channel_name) {
+                (channel.hidden, channel.persistent) = previous;
+            }
+        })
+    }
+
+    pub fn set_max_participants(&self, channel_name: &str, max_participants: usize) -> Result<(), String> {
+        let previous = {
+            let mut channel = self.get_channel(channel_name)
+                .ok_or_else(|| "Channel does not exist".to_string())?;
+            if channel.channel_type != ChannelType::Voice {
+                return Err("That's not a voice channel".to_string());
+            }
+            std::mem::replace(&mut channel.max_participants, max_participants)
+        };
+
+        self.save_channels().inspect_err(|_| {
+            if let Some(mut channel) = self.get_channel(channel_name) {
+                channel.max_participants = previous;
+            }
+        })
+    }
+
+    /// Name, type, member count and lock state of every listed channel.
+    pub fn list_channels(&self) -> Vec<(String, ChannelType, usize, bool)> {
         self.channels.values()
-            .map(|ch| (ch.name.clone(), ch.channel_type.clone(), ch.users.len()))
+            .map(lock_channel)
+            .filter(|ch| !ch.hidden)
+            .map(|ch| (ch.name.clone(), ch.channel_type.clone(), ch.users.len(), ch.locked))
             .collect()
     }
     
-    fn load_channels(&mut self) -> Result<(), String> {
+    /// Channels whose name or welcome message contains `term` (case-insensitive),
+    /// name matches first, at most `limit` of them.
+    pub fn search_channels(&self, term: &str, limit: usize) -> Vec<(String, ChannelType, usize)> {
+        let term = term.to_lowercase();
+        let mut matches: Vec<(u8, String, ChannelType, usize)> = self.channels.values()
+            .filter_map(|ch| {
+                let ch = lock_channel(ch);
+                if ch.hidden {
+                    return None;
+                }
+                let rank = if ch.name.to_lowercase().contains(&term) {
+                    0
+                } else if ch.welcome.as_ref().is_some_and(|w| w.to_lowercase().contains(&term)) {
+                    1
+                } else {
+                    return None;
+                };
+                Some((rank, ch.name.clone(), ch.channel_type.clone(), ch.users.len()))
+            })
+            .collect();
+
+        matches.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        matches.into_iter()
+            .take(limit)
+            .map(|(_, name, channel_type, users)| (name, channel_type, users))
+            .collect()
+    }
+
+    /// Open channels worth recommending for `interest`: exact name, name
+    /// prefix, name substring, then welcome message matches, busiest first
+    /// within each rank. Locked and hidden channels are left out.
+    pub fn suggest_channels(&self, interest: &str, limit: usize) -> Vec<(String, ChannelType, usize, Option<String>)> {
+        let interest = interest.to_lowercase();
+        let mut matches: Vec<(u8, String, ChannelType, usize, Option<String>)> = self.channels.values()
+            .filter_map(|ch| {
+                let ch = lock_channel(ch);
+                if ch.hidden || ch.locked {
+                    return None;
+                }
+                let name = ch.name.to_lowercase();
+                let rank = if name == interest {
+                    0
+                } else if name.starts_with(&interest) {
+                    1
+                } else if name.contains(&interest) {
+                    2
+                } else if ch.welcome.as_ref().is_some_and(|w| w.to_lowercase().contains(&interest)) {
+                    3
+                } else {
+                    return None;
+                };
+                Some((rank, ch.name.clone(), ch.channel_type.clone(), ch.users.len(), ch.welcome.clone()))
+            })
+            .collect();
+
+        matches.sort_by(|a, b| a.0.cmp(&b.0)
+            .then_with(|| b.3.cmp(&a.3))
+            .then_with(|| a.1.cmp(&b.1)));
+        matches.into_iter()
+            .take(limit)
+            .map(|(_, name, channel_type, users, welcome)| (name, channel_type, users, welcome))
+            .collect()
+    }
+
+    fn load_channels(&mut self, starter_channels: &[StarterChannel]) -> Result<(), String> {
         if !std::path::Path::new(&self.config_file).exists() {
-            // Create default channels if file doesn't exist
-            self.channels.insert("general".to_string(), Channel::new("general".to_string(), ChannelType::Text));
-            self.channels.insert("random".to_string(), Channel::new("random".to_string(), ChannelType::Text));
-            self.channels.insert("voice-lobby".to_string(), Channel::new("voice-lobby".to_string(), ChannelType::Voice));
-            self.channels.insert("gaming".to_string(), Channel::new("gaming".to_string(), ChannelType::Voice));
+            if starter_channels.is_empty() {
+                for (name, channel_type) in [
+                    ("general", ChannelType::Text),
+                    ("random", ChannelType::Text),
+                    ("voice-lobby", ChannelType::Voice),
+                    ("gaming", ChannelType::Voice),
+                ] {
+                    self.channels.insert(name.to_string(), Mutex::new(Channel::new(name.to_string(), channel_type)));
+                }
+            }
+            for starter in starter_channels {
+                let mut channel = Channel::new(starter.name.clone(), starter.channel_type.clone());
+                channel.welcome = starter.welcome.clone();
+                self.channels.insert(starter.name.clone(), Mutex::new(channel));
+            }
             return self.save_channels();
         }
         
@@ -122,15 +570,60 @@ impl ChannelManager {
         let content = fs::read_to_string(&self.config_file)
             .map_err(|e| format!("Failed to read channels file: {}", e))?;
             
-        let channels: HashMap<String, Channel> = serde_json::from_str(&content)
+        // Parse entries one by one so a single bad channel (e.g. a type this
+        // version doesn't know) doesn't take every other channel down with it
+        let entries: HashMap<String, serde_json::Value> = serde_json::from_str(&content)
             .map_err(|e| format!("Failed to parse channels file: {}", e))?;
-        
-        self.channels = channels;
+
+        let mut skipped = serde_json::Map::new();
+        for (name, value) in entries {
+            match serde_json::from_value::<Channel>(value.clone()) {
+                Ok(channel) => {
+                    self.channels.insert(name, Mutex::new(channel));
+                }
+                Err(e) => {
+                    eprintln!("Skipping channel {:?} in {}: {}", name, self.config_file, e);
+                    skipped.insert(name, value);
+                }
+            }
+        }
+        if !skipped.is_empty() {
+            self.keep_skipped_channels(skipped);
+        }
+
+        *self.save_lock.get_mut().unwrap_or_else(PoisonError::into_inner) = file_mtime(&self.config_file);
         Ok(())
     }
     
+    /// Writes entries that failed to load next to the channels file, since the
+    /// next save would otherwise drop them for good.
+    fn keep_skipped_channels(&self, skipped: serde_json::Map<String, serde_json::Value>) {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let path = format!("{}.skipped-{}", self.config_file, stamp);
+
+        let result = serde_json::to_string_pretty(&skipped)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&path, json).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => eprintln!("WARNING: {} unreadable channel entries saved to {}", skipped.len(), path),
+            Err(e) => eprintln!("WARNING: failed to save {} unreadable channel entries: {}", skipped.len(), e),
+        }
+    }
+
     fn save_channels(&self) -> Result<(), String> {
-        let json = serde_json::to_string_pretty(&self.channels)
+        let mut known_mtime = self.save_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.preserve_external_changes(*known_mtime);
+
+        // Snapshot one channel at a time so no two channel locks are ever held together
+        let snapshot: HashMap<&String, Channel> = self.channels.iter()
+            .map(|(name, channel)| (name, lock_channel(channel).clone()))
+            .filter(|(_, channel)| channel.persistent)
+            .collect();
+
+        let json = serde_json::to_string_pretty(&snapshot)
             .map_err(|e| format!("Failed to serialize channels: {}", e))?;
             
         // Write to a temporary file first, then rename for atomic operation
@@ -142,7 +635,151 @@ impl ChannelManager {
         // Atomic rename (moves temp file to final location)
         fs::rename(&temp_file, &self.config_file)
             .map_err(|e| format!("Failed to rename channels file: {}", e))?;
-            
+
+        *known_mtime = file_mtime(&self.config_file);
         Ok(())
     }
+
+    /// If the channels file changed on disk since we last loaded or saved it
+    /// (another server instance, or a hand edit), copies it aside before the
+    /// save replaces it, so those changes can be merged back by hand.
+    fn preserve_external_changes(&self, known_mtime: Option<SystemTime>) {
+        let Some(current_mtime) = file_mtime(&self.config_file) else {
+            return;
+        };
+        if known_mtime == Some(current_mtime) {
+            return;
+        }
+
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let backup = format!("{}.conflict-{}", self.config_file, stamp);
+        match fs::copy(&self.config_file, &backup) {
+            Ok(_) => eprintln!(
+                "WARNING: {} was modified outside this server; saving over it, previous contents kept in {}",
+                self.config_file, backup),
+            Err(e) => eprintln!(
+                "WARNING: {} was modified outside this server and could not be backed up ({}); saving over it",
+                self.config_file, e),
+        }
+    }
+}
+
+fn file_mtime(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    /// A scratch directory removed when the test ends.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("chatserver-channels-{}", Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn file(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+
+        /// Files in the directory whose names start with `prefix`.
+        fn files_starting_with(&self, prefix: &str) -> Vec<PathBuf> {
+            fs::read_dir(&self.0).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with(prefix))
+                .collect()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn first_run_creates_the_built_in_channels() {
+        let dir = TempDir::new();
+        let manager = ChannelManager::new_with_config(&dir.file("channels.json"), &[]);
+        for name in ["general", "random", "voice-lobby", "gaming"] {
+            assert!(manager.channel_exists(name), "{}", name);
+        }
+        assert!(Path::new(&dir.file("channels.json")).exists());
+    }
+
+    #[test]
+    fn first_run_uses_starter_channels_when_configured() {
+        let dir = TempDir::new();
+        let starters = [StarterChannel {
+            name: "lobby".to_string(),
+            channel_type: ChannelType::Text,
+            welcome: Some("Hi".to_string()),
+        }];
+        let manager = ChannelManager::new_with_config(&dir.file("channels.json"), &starters);
+        assert!(manager.channel_exists("lobby"));
+        assert!(!manager.channel_exists("general"));
+        assert_eq!(manager.get_channel("lobby").unwrap().welcome.as_deref(), Some("Hi"));
+    }
+
+    #[test]
+    fn load_skips_unknown_entries_and_keeps_them_aside() {
+        let dir = TempDir::new();
+        let path = dir.file("channels.json");
+        fs::write(&path, r#"{
+            "general": {"name": "general", "channel_type": "Text", "users": []},
+            "stage": {"name": "stage", "channel_type": "Video", "users": []}
+        }"#).unwrap();
+
+        let manager = ChannelManager::new_with_config(&path, &[]);
+        assert!(manager.channel_exists("general"));
+        assert!(!manager.channel_exists("stage"));
+
+        let skipped = dir.files_starting_with("channels.json.skipped-");
+        assert_eq!(skipped.len(), 1);
+        let kept: serde_json::Value = serde_json::from_str(&fs::read_to_string(&skipped[0]).unwrap()).unwrap();
+        assert_eq!(kept["stage"]["channel_type"], "Video");
+    }
+
+    #[test]
+    fn save_backs_up_a_file_changed_behind_our_back() {
+        let dir = TempDir::new();
+        let path = dir.file("channels.json");
+        let mut manager = ChannelManager::new_with_config(&path, &[]);
+
+        fs::write(&path, r#"{"edited": {"name": "edited", "channel_type": "Text", "users": []}}"#).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+
+        manager.create_channel("new", ChannelType::Text).unwrap();
+        let conflicts = dir.files_starting_with("channels.json.conflict-");
+        assert_eq!(conflicts.len(), 1);
+        assert!(fs::read_to_string(&conflicts[0]).unwrap().contains("edited"));
+
+        // Our own saves don't count as outside changes
+        manager.create_channel("another", ChannelType::Text).unwrap();
+        assert_eq!(dir.files_starting_with("channels.json.conflict-").len(), 1);
+    }
+
+    #[test]
+    fn slow_mode_wait_starts_only_once_a_line_is_recorded() {
+        let dir = TempDir::new();
+        let manager = ChannelManager::new_with_config(&dir.file("channels.json"), &[]);
+        manager.get_channel("general").unwrap().slow_mode = Some(Duration::from_secs(30));
+
+        // A line rejected before it was recorded leaves the sender free to retry
+        assert_eq!(manager.slow_mode_wait("general", "alice"), None);
+        assert_eq!(manager.slow_mode_wait("general", "alice"), None);
+
+        assert_eq!(manager.record_message("general", "alice", None), None);
+        assert!(manager.slow_mode_wait("general", "alice").is_some());
+        assert_eq!(manager.slow_mode_wait("general", "bob"), None);
+    }
 }
@@ -1,22 +1,245 @@
-use std::net::TcpStream;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::format::ColorScheme;
 use crate::user::User;
 use uuid::Uuid;
 
+/// Window over which channel switches are counted for debouncing.
+pub const SWITCH_WINDOW: Duration = Duration::from_secs(30);
+/// Join/leave notices announced per channel within `SWITCH_WINDOW`; a
+/// single round trip is shown, further flapping is not.
+const ANNOUNCED_SWITCHES: u32 = 2;
+/// `/join`s accepted within `SWITCH_WINDOW` before further ones are refused.
+const MAX_SWITCHES: usize = 8;
+
+/// Commands kept per connection for `/last`.
+pub const COMMAND_HISTORY_SIZE: usize = 20;
+
+const WRITE_RETRY_ATTEMPTS: u32 = 3;
+const WRITE_RETRY_BASE_DELAY: Duration = Duration::from_millis(10);
+/// Longest `ClientOutput::flush` waits for the writer thread to catch up.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An action waiting for the client's next line, such as a confirmation prompt.
+#[derive(Debug, Clone)]
+pub enum PendingAction {
+    DeleteAccount,
+    Purge { channel: String, count: usize },
+    ClearHistory { channel: String },
+}
+
+/// Why the server is closing a connection, sent as a final
+/// `DISCONNECT: <code>` line so clients can tell a ban-like refusal from
+/// something worth reconnecting after.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Quit,
+    IdleTimeout,
+    LoginTimeout,
+    AuthFailed,
+    ServerFull,
+    TooManyConnections,
+    AccountDeleted,
+    Disconnected,
+    Shutdown,
+    Restart,
+}
+
+impl DisconnectReason {
+    pub fn code(self) -> &'static str {
+        match self {
+            DisconnectReason::Quit => "quit",
+            DisconnectReason::IdleTimeout => "idle_timeout",
+            DisconnectReason::LoginTimeout => "login_timeout",
+            DisconnectReason::AuthFailed => "auth_failed",
+            DisconnectReason::ServerFull => "server_full",
+            DisconnectReason::TooManyConnections => "too_many_connections",
+            DisconnectReason::AccountDeleted => "account_deleted",
+            DisconnectReason::Disconnected => "disconnected",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::Restart => "restart",
+        }
+    }
+
+    pub fn line(self) -> String {
+        format!("DISCONNECT: {}\n", self.code())
+    }
+}
+
+/// Recent `/join`s of one connection, used to quiet join/leave notices from
+/// a client that keeps hopping between channels and to slow it down.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelSwitches {
+    joins: VecDeque<Instant>,
+    /// Channel to when its first counted join or leave happened and how many since.
+    notices: HashMap<String, (Instant, u32)>,
+}
+
+impl ChannelSwitches {
+    /// Records a switch from `old` to `new`, returning whether to announce
+    /// the leave and the join, or `None` if the connection is switching too fast.
+    pub fn record(&mut self, old: Option<&str>, new: &str) -> Option<(bool, bool)> {
+        let now = Instant::now();
+        self.joins.retain(|t| now.duration_since(*t) < SWITCH_WINDOW);
+        if self.joins.len() >= MAX_SWITCHES {
+            return None;
+        }
+        self.joins.push_back(now);
+
+        self.notices.retain(|_, (first, _)| now.duration_since(*first) < SWITCH_WINDOW);
+        let mut announce = |channel: &str| {
+            let (_, count) = self.notices.entry(channel.to_string()).or_insert((now, 0));
+            *count += 1;
+            *count <= ANNOUNCED_SWITCHES
+        };
+        let announce_leave = old.is_some_and(&mut announce);
+        let announce_join = announce(new);
+        Some((announce_leave, announce_join))
+    }
+}
+
+/// Hash and time of the last chat line this connection sent to each channel,
+/// used to drop accidental double-sends.
+#[derive(Debug, Clone, Default)]
+pub struct RecentMessages {
+    last: HashMap<String, (u64, Instant)>,
+}
+
+impl RecentMessages {
+    /// Whether `message` repeats the last line delivered to `channel` within
+    /// `window` and should be dropped.
+    pub fn is_duplicate(&mut self, channel: &str, message: &str, window: Duration) -> bool {
+        let now = Instant::now();
+        self.last.retain(|_, (_, at)| now.duration_since(*at) < window);
+        self.last.get(channel).is_some_and(|(last, _)| *last == hash_message(message))
+    }
+
+    /// Remembers `message` as the last line delivered to `channel`.
+    pub fn record(&mut self, channel: &str, message: &str) {
+        self.last.insert(channel.to_string(), (hash_message(message), Instant::now()));
+    }
+}
+
+fn hash_message(message: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    message.hash(&mut hasher);
+    hasher.finish()
+}
+
 #[derive(Debug)]
 pub struct Client {
     pub id: Uuid,
     pub stream: TcpStream,
     pub user: User,
     pub current_channel: Option<String>,
+    pub last_activity: Instant,
+    pub afk: bool,
+    /// Set once the idle-disconnect warning has been sent; cleared by activity.
+    pub idle_warned: bool,
+    pub write_failures: u32,
+    pub invisible: bool,
+    pub markdown: bool,
+    pub color_scheme: ColorScheme,
+    pub emoji: bool,
+    pub display_name: Option<String>,
+    pub presence_subscribed: bool,
+    pub last_dm_from: Option<String>,
+    /// Channels where only chat lines that @mention this user are delivered.
+    pub mentions_only: HashSet<String>,
+    pub pending_action: Option<PendingAction>,
+    /// Lines of a long response not yet shown, released a page at a time by `/more`.
+    pub paged_output: VecDeque<String>,
+    pub channel_switches: ChannelSwitches,
+    pub recent_messages: RecentMessages,
+    /// Most recent commands this connection issued, oldest first, as typed.
+    pub command_history: VecDeque<String>,
+    outbox: Option<Outbox>,
+}
+
+/// Sending side of a client's writer thread.
+#[derive(Debug, Clone)]
+struct Outbox {
+    sender: Sender<Vec<u8>>,
+    /// Bytes handed to the writer thread and not yet written to the socket.
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+    /// The writer thread's socket, shut down when the queue overflows.
+    socket: Arc<TcpStream>,
+}
+
+impl Outbox {
+    fn queue(&self, data: &[u8]) -> io::Result<()> {
+        if self.queued.fetch_add(data.len(), Ordering::SeqCst) + data.len() > self.max_queued {
+            self.queued.fetch_sub(data.len(), Ordering::SeqCst);
+            // Closing the socket ends the client's read loop, which cleans it up
+            let _ = self.socket.shutdown(Shutdown::Both);
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "output queue full"));
+        }
+        self.sender.send(data.to_vec()).map_err(|_| {
+            self.queued.fetch_sub(data.len(), Ordering::SeqCst);
+            io::Error::from(ErrorKind::BrokenPipe)
+        })
+    }
+}
+
+/// What a broadcast needs from a client: its id, the preferences lines are
+/// rendered with and a handle on its writer queue. Much cheaper to collect
+/// under the clients lock than `Client::try_clone`, which duplicates the
+/// socket and copies every history.
+#[derive(Debug, Clone)]
+pub struct Recipient {
+    pub id: Uuid,
+    pub name: String,
+    pub write_failures: u32,
+    pub invisible: bool,
+    pub markdown: bool,
+    pub color_scheme: ColorScheme,
+    pub emoji: bool,
+    pub mentions_only: HashSet<String>,
+    outbox: Outbox,
+}
+
+impl Recipient {
+    /// Queues the buffer for the client's writer thread.
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.outbox.queue(data)
+    }
 }
 
 impl Client {
-    pub fn new(stream: TcpStream, user: User) -> Result<Self, std::io::Error> {
+    pub fn new(stream: TcpStream, user: User, channel: &str) -> Result<Self, std::io::Error> {
         Ok(Client {
             id: Uuid::new_v4(),
             stream,
             user,
-            current_channel: Some("general".to_string()),
+            current_channel: Some(channel.to_string()),
+            last_activity: Instant::now(),
+            afk: false,
+            idle_warned: false,
+            write_failures: 0,
+            invisible: false,
+            markdown: false,
+            color_scheme: ColorScheme::Default,
+            emoji: true,
+            display_name: None,
+            presence_subscribed: false,
+            last_dm_from: None,
+            mentions_only: HashSet::new(),
+            pending_action: None,
+            paged_output: VecDeque::new(),
+            channel_switches: ChannelSwitches::default(),
+            recent_messages: RecentMessages::default(),
+            command_history: VecDeque::new(),
+            outbox: None,
         })
     }
     
@@ -26,7 +249,226 @@ impl Client {
             stream: self.stream.try_clone()?,
             user: self.user.clone(),
             current_channel: self.current_channel.clone(),
+            last_activity: self.last_activity,
+            afk: self.afk,
+            idle_warned: self.idle_warned,
+            write_failures: self.write_failures,
+            invisible: self.invisible,
+            markdown: self.markdown,
+            color_scheme: self.color_scheme,
+            emoji: self.emoji,
+            display_name: self.display_name.clone(),
+            presence_subscribed: self.presence_subscribed,
+            last_dm_from: self.last_dm_from.clone(),
+            mentions_only: self.mentions_only.clone(),
+            pending_action: self.pending_action.clone(),
+            paged_output: self.paged_output.clone(),
+            channel_switches: self.channel_switches.clone(),
+            recent_messages: self.recent_messages.clone(),
+            command_history: self.command_history.clone(),
+            outbox: self.outbox.clone(),
+        })
+    }
+
+    /// Adds `command` to the history, dropping the oldest past `COMMAND_HISTORY_SIZE`.
+    pub fn record_command(&mut self, command: &str) {
+        if self.command_history.len() == COMMAND_HISTORY_SIZE {
+            self.command_history.pop_front();
+        }
+        self.command_history.push_back(command.to_string());
+    }
+
+    /// Routes `send` through a dedicated writer thread, so a client that stops
+    /// reading only ever blocks its own writer, never the thread broadcasting
+    /// to it. Everything queued within `window` is coalesced into a single
+    /// write; a zero window writes as soon as the thread picks a message up.
+    /// Order is preserved. A client whose unwritten output would exceed
+    /// `max_queued` bytes is disconnected.
+    pub fn start_writer(&mut self, window: Duration, max_queued: usize) -> Result<(), std::io::Error> {
+        let socket = Arc::new(self.stream.try_clone()?);
+        let stream = Arc::clone(&socket);
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let written = Arc::clone(&queued);
+
+        thread::spawn(move || {
+            while let Ok(mut batch) = rx.recv() {
+                let deadline = Instant::now() + window;
+                let mut closed = false;
+                while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                    match rx.recv_timeout(remaining) {
+                        Ok(more) => batch.extend_from_slice(&more),
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            closed = true;
+                            break;
+                        }
+                    }
+                }
+
+                // Dropping the receiver makes later sends fail, which reaps the client
+                let result = write_with_retry(&mut &*stream, &batch);
+                written.fetch_sub(batch.len(), Ordering::SeqCst);
+                if result.is_err() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
+                }
+                if closed {
+                    break;
+                }
+            }
+        });
+
+        self.outbox = Some(Outbox { sender: tx, queued, max_queued, socket });
+        Ok(())
+    }
+
+    /// Queues the buffer for the writer thread, or writes it directly if none
+    /// was started.
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        queue_output(self.outbox.as_ref(), &mut self.stream, data)
+    }
+
+    /// A handle for broadcasting to this client, or `None` before
+    /// `start_writer`; every client in the server's map has one.
+    pub fn recipient(&self) -> Option<Recipient> {
+        Some(Recipient {
+            id: self.id,
+            name: self.user.name.clone(),
+            write_failures: self.write_failures,
+            invisible: self.invisible,
+            markdown: self.markdown,
+            color_scheme: self.color_scheme,
+            emoji: self.emoji,
+            mentions_only: self.mentions_only.clone(),
+            outbox: self.outbox.clone()?,
         })
     }
+
+    /// A `Write` handle for this connection's own replies that shares the
+    /// queue `send` uses, so replies and broadcasts arrive in the order they
+    /// were produced.
+    pub fn output(&self) -> io::Result<ClientOutput> {
+        Ok(ClientOutput {
+            stream: self.stream.try_clone()?,
+            outbox: self.outbox.clone(),
+        })
+    }
+}
+
+fn queue_output(outbox: Option<&Outbox>, stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    match outbox {
+        Some(outbox) => outbox.queue(data),
+        None => write_with_retry(stream, data),
+    }
 }
 
+/// Writes to a client through its writer thread; see `Client::output`.
+pub struct ClientOutput {
+    stream: TcpStream,
+    outbox: Option<Outbox>,
+}
+
+impl Write for ClientOutput {
+    /// Queues all of `buf` at once, so a `write_all` is never split between
+    /// batches.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        queue_output(self.outbox.as_ref(), &mut self.stream, buf)?;
+        Ok(buf.len())
+    }
+
+    /// Waits, up to `FLUSH_TIMEOUT`, until the writer thread has written
+    /// everything queued so far.
+    fn flush(&mut self) -> io::Result<()> {
+        let Some(outbox) = &self.outbox else {
+            return self.stream.flush();
+        };
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        while outbox.queued.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(FLUSH_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+/// Writes the whole buffer, retrying transient errors with exponential backoff.
+///
+/// Only bytes not yet accepted by the socket are retried, so a message is
+/// never duplicated. Hard errors and exhausted retries are returned.
+fn write_with_retry(stream: &mut impl Write, mut data: &[u8]) -> io::Result<()> {
+    let mut attempt = 0;
+    while !data.is_empty() {
+        match stream.write(data) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(n) => {
+                data = &data[n..];
+                attempt = 0;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if is_transient(&e) && attempt < WRITE_RETRY_ATTEMPTS => {
+                thread::sleep(WRITE_RETRY_BASE_DELAY * 2u32.pow(attempt));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_switches_stop_announcing_a_busy_channel() {
+        let mut switches = ChannelSwitches::default();
+        assert_eq!(switches.record(None, "a"), Some((false, true)));
+        assert_eq!(switches.record(Some("a"), "b"), Some((true, true)));
+        // `a` has now been announced twice (join and leave)
+        assert_eq!(switches.record(Some("b"), "a"), Some((true, false)));
+    }
+
+    #[test]
+    fn channel_switches_refuse_past_the_limit() {
+        let mut switches = ChannelSwitches::default();
+        for i in 0..MAX_SWITCHES {
+            assert!(switches.record(None, &format!("c{}", i)).is_some());
+        }
+        assert_eq!(switches.record(None, "one-more"), None);
+    }
+
+    #[test]
+    fn recent_messages_drop_repeats_per_channel() {
+        let window = Duration::from_secs(60);
+        let mut recent = RecentMessages::default();
+        assert!(!recent.is_duplicate("general", "hi", window));
+        recent.record("general", "hi");
+        assert!(recent.is_duplicate("general", "hi", window));
+        assert!(!recent.is_duplicate("random", "hi", window));
+        assert!(!recent.is_duplicate("general", "hello", window));
+        recent.record("general", "hello");
+        assert!(!recent.is_duplicate("general", "hi", window));
+    }
+
+    #[test]
+    fn recent_messages_only_remember_delivered_lines() {
+        let window = Duration::from_secs(60);
+        let mut recent = RecentMessages::default();
+        // A line that was checked but then throttled can be sent again
+        assert!(!recent.is_duplicate("general", "hi", window));
+        assert!(!recent.is_duplicate("general", "hi", window));
+    }
+
+    #[test]
+    fn recent_messages_forget_after_the_window() {
+        let window = Duration::from_millis(20);
+        let mut recent = RecentMessages::default();
+        recent.record("general", "hi");
+        thread::sleep(window * 2);
+        assert!(!recent.is_duplicate("general", "hi", window));
+    }
+}
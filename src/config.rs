@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::broadcast::RateLimit;
+use crate::channel::StarterChannel;
+use crate::user::Role;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server_name: String,
+    pub default_channel: String,
+    pub starter_channels: Vec<StarterChannel>,
+    pub onboarding_message: String,
+    pub command_prefix: String,
+    pub data_dir: String,
+    pub users_file: String,
+    pub channels_file: String,
+    pub mail_file: String,
+    pub mail_expiry_days: u64,
+    pub max_connections_per_ip: usize,
+    pub max_accounts: Option<usize>,
+    pub reserved_usernames: Vec<String>,
+    pub banned_usernames: Vec<String>,
+    pub health_check_port: Option<u16>,
+    pub listen_backlog: i32,
+    pub batch_ms: u64,
+    pub max_output_queue_kb: usize,
+    pub broadcast_budget_per_sec: u64,
+    pub rate_limits: HashMap<Role, RateLimit>,
+    pub page_lines: usize,
+    pub poll_duration_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub idle_warning_secs: u64,
+    pub idle_disconnect_message: String,
+    pub login_timeout_message: String,
+    pub afk_timeout_secs: u64,
+    pub voice_reconnect_grace_secs: u64,
+    pub voice_idle_kick: bool,
+    pub voice_idle_timeout_secs: u64,
+    pub log_messages: bool,
+    pub log_dir: String,
+    pub log_max_bytes: u64,
+    pub log_retention_days: u64,
+    pub password_hash: String,
+    pub summon_webhook_url: Option<String>,
+    pub geoip_database: Option<String>,
+    pub require_email: bool,
+    pub hash_emails: bool,
+    pub smtp_url: Option<String>,
+    pub smtp_from: Option<String>,
+    pub webhook_port: Option<u16>,
+    pub webhook_token: Option<String>,
+    pub auto_slow_mode: bool,
+    pub slow_mode_threshold: usize,
+    pub slow_mode_delay_secs: u64,
+    pub dedupe_window_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_name: "ChatServer".to_string(),
+            default_channel: "general".to_string(),
+            starter_channels: Vec::new(),
+            onboarding_message: "Be kind and stay on topic. Type /help for the command list, /channels to see where people are talking and /join <channel> to switch.".to_string(),
+            command_prefix: "/".to_string(),
+            data_dir: ".".to_string(),
+            users_file: "users.json".to_string(),
+            channels_file: "channels.json".to_string(),
+            mail_file: "mail.json".to_string(),
+            mail_expiry_days: 30,
+            max_connections_per_ip: 5,
+            max_accounts: None,
+            reserved_usernames: Vec::new(),
+            banned_usernames: Vec::new(),
+            health_check_port: None,
+            listen_backlog: 128,
+            batch_ms: 0,
+            max_output_queue_kb: 1024,
+            broadcast_budget_per_sec: 20000,
+            rate_limits: HashMap::new(),
+            page_lines: 20,
+            poll_duration_secs: 300,
+            idle_timeout_secs: 1800,
+            idle_warning_secs: 60,
+            idle_disconnect_message: "Disconnected after a long time without activity. Reconnect and log in again to pick up where you left off.".to_string(),
+            login_timeout_message: "Timed out waiting for login. Reconnect to try again.".to_string(),
+            afk_timeout_secs: 600,
+            voice_reconnect_grace_secs: 30,
+            voice_idle_kick: false,
+            voice_idle_timeout_secs: 900,
+            log_messages: false,
+            log_dir: "logs".to_string(),
+            log_max_bytes: 10 * 1024 * 1024,
+            log_retention_days: 90,
+            password_hash: "bcrypt".to_string(),
+            summon_webhook_url: None,
+            geoip_database: None,
+            require_email: false,
+            hash_emails: false,
+            smtp_url: None,
+            smtp_from: None,
+            webhook_port: None,
+            webhook_token: None,
+            auto_slow_mode: false,
+            slow_mode_threshold: 20,
+            slow_mode_delay_secs: 5,
+            dedupe_window_ms: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file, falling back to defaults for a missing file or
+    /// missing keys. `USERS_FILE` and `CHANNELS_FILE` in the environment
+    /// override the data file paths.
+    pub fn load(file_path: &str) -> Self {
+        let mut config = if Path::new(file_path).exists() {
+            let result = fs::read_to_string(file_path)
+                .map_err(|e| format!("Failed to read config file: {}", e))
+                .and_then(|content| serde_json::from_str(&content)
+                    .map_err(|e| format!("Failed to parse config file: {}", e)));
+
+            result.unwrap_or_else(|e| {
+                eprintln!("{}, using defaults", e);
+                Config::default()
+            })
+        } else {
+            Config::default()
+        };
+
+        if let Ok(path) = env::var("USERS_FILE") {
+            config.users_file = path;
+        }
+        if let Ok(path) = env::var("CHANNELS_FILE") {
+            config.channels_file = path;
+        }
+        config
+    }
+
+    /// Resolves a persisted file's path against `data_dir`; absolute paths are kept as they are.
+    pub fn data_path(&self, file: &str) -> String {
+        Path::new(&self.data_dir).join(file).to_string_lossy().into_owned()
+    }
+
+    pub fn mail_expiry(&self) -> Duration {
+        Duration::from_secs(self.mail_expiry_days * 24 * 60 * 60)
+    }
+
+    /// Prefix that marks a line as a command; an empty or whitespace setting falls back to `/`.
+    pub fn command_prefix(&self) -> &str {
+        let prefix = self.command_prefix.trim();
+        if prefix.is_empty() { "/" } else { prefix }
+    }
+
+    /// How long log files are kept; `None` keeps them forever.
+    pub fn log_retention(&self) -> Option<Duration> {
+        (self.log_retention_days > 0).then(|| Duration::from_secs(self.log_retention_days * 24 * 60 * 60))
+    }
+
+    pub fn poll_duration(&self) -> Duration {
+        Duration::from_secs(self.poll_duration_secs)
+    }
+
+    /// Output batching window; `None` writes every message immediately.
+    pub fn batch_window(&self) -> Option<Duration> {
+        (self.batch_ms > 0).then(|| Duration::from_millis(self.batch_ms))
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    /// How long before the idle disconnect the user is warned; `0` disables the warning.
+    pub fn idle_warning(&self) -> Duration {
+        Duration::from_secs(self.idle_warning_secs)
+    }
+
+    pub fn afk_timeout(&self) -> Duration {
+        Duration::from_secs(self.afk_timeout_secs)
+    }
+
+    pub fn voice_reconnect_grace(&self) -> Duration {
+        Duration::from_secs(self.voice_reconnect_grace_secs)
+    }
+
+    /// Inactivity after which a voice session is ended, if idle-kick is on.
+    /// Until audio exists this is measured on the control connection.
+    pub fn voice_idle_timeout(&self) -> Option<Duration> {
+        (self.voice_idle_kick && self.voice_idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(self.voice_idle_timeout_secs))
+    }
+
+    /// `(messages per rate window, per-user delay)` when automatic slow mode is enabled.
+    pub fn auto_slow_mode(&self) -> Option<(usize, Duration)> {
+        (self.auto_slow_mode && self.slow_mode_threshold > 0)
+            .then(|| (self.slow_mode_threshold, Duration::from_secs(self.slow_mode_delay_secs)))
+    }
+
+    /// Window within which an identical line to the same channel is dropped as a double-send.
+    pub fn dedupe_window(&self) -> Option<Duration> {
+        (self.dedupe_window_ms > 0).then(|| Duration::from_millis(self.dedupe_window_ms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_uses_defaults() {
+        let config: Config = serde_json::from_str("{}").unwrap();
+        let defaults = Config::default();
+        assert_eq!(serde_json::to_value(&config).unwrap(), serde_json::to_value(&defaults).unwrap());
+        assert_eq!(config.default_channel, "general");
+        assert_eq!(config.password_hash, "bcrypt");
+        assert_eq!(config.idle_timeout(), Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn missing_keys_keep_their_defaults() {
+        let config: Config = serde_json::from_str(r#"{"server_name": "Test", "batch_ms": 25}"#).unwrap();
+        assert_eq!(config.server_name, "Test");
+        assert_eq!(config.batch_window(), Some(Duration::from_millis(25)));
+        assert_eq!(config.max_connections_per_ip, 5);
+        assert_eq!(config.command_prefix(), "/");
+    }
+
+    #[test]
+    fn zero_disables_optional_features() {
+        let config = Config::default();
+        assert_eq!(config.batch_window(), None);
+        assert_eq!(config.dedupe_window(), None);
+        assert_eq!(config.voice_idle_timeout(), None);
+        assert_eq!(config.auto_slow_mode(), None);
+
+        let config = Config { log_retention_days: 0, ..Config::default() };
+        assert_eq!(config.log_retention(), None);
+    }
+
+    #[test]
+    fn blank_command_prefix_falls_back_to_slash() {
+        let config = Config { command_prefix: "  ".to_string(), ..Config::default() };
+        assert_eq!(config.command_prefix(), "/");
+        let config = Config { command_prefix: "!".to_string(), ..Config::default() };
+        assert_eq!(config.command_prefix(), "!");
+    }
+
+    #[test]
+    fn rate_limits_are_keyed_by_role() {
+        let config: Config = serde_json::from_str(
+            r#"{"rate_limits": {"User": {"messages_per_minute": 20, "burst": 5}}}"#).unwrap();
+        let limit = config.rate_limits[&Role::User];
+        assert_eq!((limit.messages_per_minute, limit.burst), (20, 5));
+        assert!(!config.rate_limits.contains_key(&Role::Moderator));
+    }
+}
@@ -0,0 +1,40 @@
+use rand::Rng;
+
+pub const MAX_DICE: u32 = 100;
+pub const MAX_SIDES: u32 = 1000;
+
+/// A parsed `NdM` roll such as `2d6`; `d20` is shorthand for `1d20`.
+#[derive(Debug, Clone, Copy)]
+pub struct Dice {
+    pub count: u32,
+    pub sides: u32,
+}
+
+impl Dice {
+    pub fn parse(notation: &str) -> Result<Self, String> {
+        let notation = notation.to_ascii_lowercase();
+        let (count, sides) = notation.split_once('d')
+            .ok_or_else(|| "Dice must be written as NdM, e.g. 2d6".to_string())?;
+
+        let count = if count.is_empty() {
+            1
+        } else {
+            count.parse::<u32>().map_err(|_| format!("Invalid number of dice: {}", count))?
+        };
+        let sides = sides.parse::<u32>().map_err(|_| format!("Invalid number of sides: {}", sides))?;
+
+        if !(1..=MAX_DICE).contains(&count) {
+            return Err(format!("Number of dice must be between 1 and {}", MAX_DICE));
+        }
+        if !(2..=MAX_SIDES).contains(&sides) {
+            return Err(format!("Number of sides must be between 2 and {}", MAX_SIDES));
+        }
+
+        Ok(Dice { count, sides })
+    }
+
+    pub fn roll(&self) -> Vec<u32> {
+        let mut rng = rand::rng();
+        (0..self.count).map(|_| rng.random_range(1..=self.sides)).collect()
+    }
+}
@@ -0,0 +1,104 @@
+use std::borrow::Cow;
+
+/// Looks up the emoji for a shortcode name (without the surrounding colons).
+fn lookup(name: &str) -> Option<&'static str> {
+    let emoji = match name {
+        "smile" => "😄",
+        "grin" => "😁",
+        "joy" => "😂",
+        "laughing" => "😆",
+        "wink" => "😉",
+        "blush" => "😊",
+        "heart_eyes" => "😍",
+        "thinking" => "🤔",
+        "neutral_face" => "😐",
+        "sweat_smile" => "😅",
+        "sunglasses" => "😎",
+        "cry" => "😢",
+        "sob" => "😭",
+        "angry" => "😠",
+        "rage" => "😡",
+        "scream" => "😱",
+        "sleeping" => "😴",
+        "confused" => "😕",
+        "upside_down" => "🙃",
+        "eyes" => "👀",
+        "thumbsup" | "+1" => "👍",
+        "thumbsdown" | "-1" => "👎",
+        "clap" => "👏",
+        "wave" => "👋",
+        "ok_hand" => "👌",
+        "pray" => "🙏",
+        "muscle" => "💪",
+        "raised_hands" => "🙌",
+        "shrug" => "🤷",
+        "facepalm" => "🤦",
+        "heart" => "❤️",
+        "broken_heart" => "💔",
+        "fire" => "🔥",
+        "star" => "⭐",
+        "sparkles" => "✨",
+        "tada" => "🎉",
+        "rocket" => "🚀",
+        "100" => "💯",
+        "check" | "white_check_mark" => "✅",
+        "x" => "❌",
+        "warning" => "⚠️",
+        "question" => "❓",
+        "bulb" => "💡",
+        "coffee" => "☕",
+        "pizza" => "🍕",
+        "beer" => "🍺",
+        "cake" => "🍰",
+        "sun" => "☀️",
+        "moon" => "🌙",
+        "rainbow" => "🌈",
+        "zap" => "⚡",
+        "skull" => "💀",
+        "ghost" => "👻",
+        "robot" => "🤖",
+        "cat" => "🐱",
+        "dog" => "🐶",
+        "bug" => "🐛",
+        "computer" => "💻",
+        "video_game" => "🎮",
+        "musical_note" => "🎵",
+        "zzz" => "💤",
+        _ => return None,
+    };
+    Some(emoji)
+}
+
+fn is_shortcode_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '+' | '-')
+}
+
+/// Replaces known `:shortcode:` sequences with Unicode emoji. Unknown
+/// shortcodes and stray colons are left as typed.
+pub fn expand_shortcodes(text: &str) -> Cow<'_, str> {
+    if !text.contains(':') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find(':') {
+        output.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let name_len = after.find(|c| !is_shortcode_char(c)).unwrap_or(after.len());
+
+        if name_len > 0
+            && after[name_len..].starts_with(':')
+            && let Some(emoji) = lookup(&after[..name_len]) {
+            output.push_str(emoji);
+            rest = &after[name_len + 1..];
+        } else {
+            // The closing colon of a failed match may still open the next shortcode
+            output.push(':');
+            rest = after;
+        }
+    }
+    output.push_str(rest);
+
+    Cow::Owned(output)
+}
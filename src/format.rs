@@ -0,0 +1,140 @@
+use std::borrow::Cow;
+use serde::{Deserialize, Serialize};
+
+const ANSI_RESET: &str = "\x1b[0m";
+
+/// ANSI sequences a color scheme uses for each kind of styled text.
+struct Palette {
+    bold: &'static str,
+    italic: &'static str,
+    code: &'static str,
+    /// Style for the sender's name in chat lines; `None` leaves it plain.
+    sender: Option<&'static str>,
+}
+
+/// Named palettes a user can pick with `/colorscheme`, for different
+/// terminal backgrounds or accessibility needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorScheme {
+    #[default]
+    Default,
+    Solarized,
+    HighContrast,
+    /// No colors at all, only bold, underline and reverse video.
+    Monochrome,
+}
+
+pub const COLOR_SCHEMES: [ColorScheme; 4] = [
+    ColorScheme::Default,
+    ColorScheme::Solarized,
+    ColorScheme::HighContrast,
+    ColorScheme::Monochrome,
+];
+
+impl ColorScheme {
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorScheme::Default => "default",
+            ColorScheme::Solarized => "solarized",
+            ColorScheme::HighContrast => "highcontrast",
+            ColorScheme::Monochrome => "monochrome",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        COLOR_SCHEMES.into_iter().find(|scheme| scheme.name().eq_ignore_ascii_case(name))
+    }
+
+    fn palette(self) -> Palette {
+        match self {
+            ColorScheme::Default => Palette {
+                bold: "\x1b[1m",
+                italic: "\x1b[3m",
+                code: "\x1b[36m",
+                sender: None,
+            },
+            ColorScheme::Solarized => Palette {
+                bold: "\x1b[1;38;5;136m",
+                italic: "\x1b[3;38;5;37m",
+                code: "\x1b[38;5;64m",
+                sender: Some("\x1b[38;5;33m"),
+            },
+            ColorScheme::HighContrast => Palette {
+                bold: "\x1b[1;97m",
+                italic: "\x1b[3;93m",
+                code: "\x1b[1;96m",
+                sender: Some("\x1b[1;97m"),
+            },
+            ColorScheme::Monochrome => Palette {
+                bold: "\x1b[1m",
+                italic: "\x1b[4m",
+                code: "\x1b[7m",
+                sender: Some("\x1b[1m"),
+            },
+        }
+    }
+
+    /// The sender's name styled for this scheme.
+    pub fn sender<'a>(self, name: &'a str) -> Cow<'a, str> {
+        match self.palette().sender {
+            Some(style) => Cow::Owned(format!("{}{}{}", style, name, ANSI_RESET)),
+            None => Cow::Borrowed(name),
+        }
+    }
+}
+
+/// Renders `*bold*`, `_italic_` and `` `code` `` spans as ANSI formatting
+/// in the given scheme's palette.
+///
+/// A marker only opens a span at a word boundary and only closes it before
+/// one, so identifiers like `snake_case_name` are left untouched. Markers
+/// without a matching partner are kept as plain text.
+pub fn render_markdown(text: &str, scheme: ColorScheme) -> String {
+    let palette = scheme.palette();
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = String::with_capacity(text.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        let style = match chars[i] {
+            '*' => Some(palette.bold),
+            '_' => Some(palette.italic),
+            '`' => Some(palette.code),
+            _ => None,
+        };
+
+        if let Some(style) = style
+            && let Some(end) = find_closing(&chars, i) {
+            let inner: String = chars[i + 1..end].iter().collect();
+            output.push_str(style);
+            output.push_str(&inner);
+            output.push_str(ANSI_RESET);
+            i = end + 1;
+            continue;
+        }
+
+        output.push(chars[i]);
+        i += 1;
+    }
+
+    output
+}
+
+fn find_closing(chars: &[char], open: usize) -> Option<usize> {
+    let marker = chars[open];
+    if open > 0 && chars[open - 1].is_alphanumeric() {
+        return None;
+    }
+
+    let first = *chars.get(open + 1)?;
+    if first.is_whitespace() || first == marker {
+        return None;
+    }
+
+    (open + 2..chars.len()).find(|&close| {
+        chars[close] == marker
+            && !chars[close - 1].is_whitespace()
+            && chars.get(close + 1).is_none_or(|c| !c.is_alphanumeric())
+    })
+}
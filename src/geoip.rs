@@ -0,0 +1,60 @@
+use std::net::IpAddr;
+use maxminddb::{Reader, geoip2};
+
+/// Coarse location lookups for moderation tools, backed by a MaxMind
+/// GeoLite2/GeoIP2 City database. Without a configured database every
+/// lookup returns `None`.
+pub struct GeoIp {
+    reader: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub fn open(path: Option<&str>) -> Self {
+        let reader = path.and_then(|path| match Reader::open_readfile(path) {
+            Ok(reader) => Some(reader),
+            Err(e) => {
+                eprintln!("Failed to open GeoIP database {}: {}; geolocation disabled", path, e);
+                None
+            }
+        });
+        GeoIp { reader }
+    }
+
+    /// `"City, CC"`, `"CC"`, `"local"` for private and loopback addresses, or
+    /// `"unknown"`. `None` when geolocation is disabled.
+    pub fn locate(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.as_ref()?;
+        if is_local(ip) {
+            return Some("local".to_string());
+        }
+
+        let Ok(record) = reader.lookup::<geoip2::City>(ip) else {
+            return Some("unknown".to_string());
+        };
+        let country = record.country.and_then(|country| country.iso_code);
+        let city = record.city
+            .and_then(|city| city.names)
+            .and_then(|names| names.get("en").copied());
+
+        Some(match (city, country) {
+            (Some(city), Some(country)) => format!("{}, {}", city, country),
+            (None, Some(country)) => country.to_string(),
+            _ => "unknown".to_string(),
+        })
+    }
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_local(IpAddr::V4(mapped));
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback() || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00 // unique local
+                || (first & 0xffc0) == 0xfe80 // link local
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::io::{self, Read};
+
+pub const MAX_LINE_LENGTH: usize = 4096;
+const BUFFER_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum Line {
+    Complete(String),
+    TooLong,
+    /// The line contained a NUL byte and was dropped.
+    Invalid,
+    /// The line was not valid UTF-8 and was dropped.
+    InvalidUtf8,
+}
+
+/// Splits a byte stream into newline-terminated lines (`\n` or `\r\n`).
+///
+/// Bytes that arrive after a newline stay buffered for the next call, so a
+/// line split across several reads (or several lines in one read) is framed
+/// correctly. Lines longer than `MAX_LINE_LENGTH` are reported once as
+/// `Line::TooLong` and the rest of them is dropped up to the next newline,
+/// which keeps the buffer bounded no matter what the client sends. Lines
+/// containing NUL bytes are reported as `Line::Invalid` so they never reach
+/// command parsing, channel lookups or file names. Lines are decoded only
+/// once complete, so a multibyte character split across reads is whole by
+/// then; anything that still isn't UTF-8 is reported as `Line::InvalidUtf8`
+/// instead of being patched up with replacement characters.
+pub struct LineReader {
+    pending: Vec<u8>,
+    buffer: Vec<u8>,
+    discarding: bool,
+}
+
+impl LineReader {
+    pub fn new() -> Self {
+        LineReader {
+            pending: Vec::with_capacity(BUFFER_SIZE),
+            buffer: vec![0u8; BUFFER_SIZE],
+            discarding: false,
+        }
+    }
+
+    /// Returns the next line, or `Ok(None)` once the peer has closed the connection.
+    pub fn read_line<R: Read>(&mut self, stream: &mut R) -> io::Result<Option<Line>> {
+        loop {
+            if let Some(line) = self.take_line() {
+                return Ok(Some(line));
+            }
+
+            let n = stream.read(&mut self.buffer)?;
+            if n == 0 {
+                // Deliver an unterminated final line before reporting the close
+                if self.discarding || self.pending.is_empty() {
+                    self.pending.clear();
+                    return Ok(None);
+                }
+                let line = frame_line(&self.pending);
+                self.pending.clear();
+                return Ok(Some(line));
+            }
+
+            self.pending.extend_from_slice(&self.buffer[..n]);
+        }
+    }
+
+    fn take_line(&mut self) -> Option<Line> {
+        loop {
+            let Some(pos) = self.pending.iter().position(|&b| b == b'\n') else {
+                if self.pending.len() > MAX_LINE_LENGTH {
+                    self.pending.clear();
+                    if !self.discarding {
+                        self.discarding = true;
+                        return Some(Line::TooLong);
+                    }
+                }
+                return None;
+            };
+
+            let raw: Vec<u8> = self.pending.drain(..=pos).collect();
+            if self.discarding {
+                // End of an oversized line that was already reported
+                self.discarding = false;
+                continue;
+            }
+
+            if pos > MAX_LINE_LENGTH {
+                return Some(Line::TooLong);
+            }
+
+            return Some(frame_line(&raw[..pos]));
+        }
+    }
+}
+
+fn frame_line(bytes: &[u8]) -> Line {
+    if bytes.contains(&0) {
+        return Line::Invalid;
+    }
+    match decode_line(bytes) {
+        Some(line) => Line::Complete(line),
+        None => Line::InvalidUtf8,
+    }
+}
+
+/// Decodes a line without its terminator, accepting both `\n` and `\r\n`
+/// endings so Windows telnet clients parse the same as Unix ones.
+fn decode_line(bytes: &[u8]) -> Option<String> {
+    let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+    std::str::from_utf8(bytes).ok().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out one chunk per `read`, as a socket might.
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    fn read_all(chunks: &[&[u8]]) -> Vec<Line> {
+        let mut stream = Chunks(chunks.iter().map(|chunk| chunk.to_vec()).collect());
+        let mut reader = LineReader::new();
+        let mut lines = Vec::new();
+        while let Some(line) = reader.read_line(&mut stream).unwrap() {
+            lines.push(line);
+        }
+        lines
+    }
+
+    fn complete(line: &Line) -> &str {
+        match line {
+            Line::Complete(text) => text,
+            other => panic!("expected a complete line, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frames_lines_across_reads() {
+        let lines = read_all(&[b"hel", b"lo\nwor", b"ld\n", b"tail"]);
+        let texts: Vec<&str> = lines.iter().map(complete).collect();
+        assert_eq!(texts, ["hello", "world", "tail"]);
+    }
+
+    #[test]
+    fn strips_crlf_but_not_a_lone_cr() {
+        let lines = read_all(&[b"one\r\ntwo\rthree\n"]);
+        assert_eq!(complete(&lines[0]), "one");
+        assert_eq!(complete(&lines[1]), "two\rthree");
+    }
+
+    #[test]
+    fn reports_over_long_line_once_and_recovers() {
+        let long = vec![b'x'; MAX_LINE_LENGTH + 10];
+        let lines = read_all(&[&long[..BUFFER_SIZE], &long[BUFFER_SIZE..], b"\nnext\n"]);
+        assert_eq!(lines.len(), 2);
+        assert!(matches!(lines[0], Line::TooLong));
+        assert_eq!(complete(&lines[1]), "next");
+    }
+
+    #[test]
+    fn rejects_nul_bytes() {
+        let lines = read_all(&[b"bad\0line\nok\n"]);
+        assert!(matches!(lines[0], Line::Invalid));
+        assert_eq!(complete(&lines[1]), "ok");
+    }
+
+    #[test]
+    fn rejects_invalid_utf8_but_joins_split_characters() {
+        let lines = read_all(&[b"\xff\xfe\n", "caf\u{e9}".as_bytes().split_last().unwrap().1, b"\xa9\n"]);
+        assert!(matches!(lines[0], Line::InvalidUtf8));
+        assert_eq!(complete(&lines[1]), "café");
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_MAILBOX_SIZE: usize = 50;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mail {
+    pub from: String,
+    pub sent_at: u64,
+    pub text: String,
+}
+
+/// Stores messages for users to read on their next login, persisted as JSON.
+/// Messages older than `max_age` are dropped.
+pub struct MailboxManager {
+    file_path: String,
+    mailboxes: HashMap<String, Vec<Mail>>,
+    max_age: Duration,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl MailboxManager {
+    pub fn new(file_path: &str, max_age: Duration) -> Self {
+        let mailboxes = if Path::new(file_path).exists() {
+            fs::read_to_string(file_path)
+                .map_err(|e| format!("Failed to read mail file: {}", e))
+                .and_then(|content| serde_json::from_str(&content)
+                    .map_err(|e| format!("Failed to parse mail file: {}", e)))
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    HashMap::new()
+                })
+        } else {
+            HashMap::new()
+        };
+
+        MailboxManager {
+            file_path: file_path.to_string(),
+            mailboxes,
+            max_age,
+        }
+    }
+
+    pub fn send(&mut self, recipient: &str, from: &str, text: &str) -> Result<(), String> {
+        self.expire(recipient);
+        let mailbox = self.mailboxes.entry(recipient.to_string()).or_default();
+        if mailbox.len() >= MAX_MAILBOX_SIZE {
+            return Err(format!("{}'s mailbox is full", recipient));
+        }
+
+        mailbox.push(Mail {
+            from: from.to_string(),
+            sent_at: now_secs(),
+            text: text.to_string(),
+        });
+
+        self.save().inspect_err(|_| {
+            if let Some(mailbox) = self.mailboxes.get_mut(recipient) {
+                mailbox.pop();
+            }
+        })
+    }
+
+    /// The user's unexpired messages, oldest first.
+    pub fn messages(&mut self, username: &str) -> Vec<Mail> {
+        self.expire(username);
+        self.mailboxes.get(username).cloned().unwrap_or_default()
+    }
+
+    /// Deletes all of the user's messages, returning how many there were.
+    pub fn clear(&mut self, username: &str) -> Result<usize, String> {
+        let Some(removed) = self.mailboxes.remove(username) else {
+            return Ok(0);
+        };
+
+        let count = removed.len();
+        self.save().inspect_err(|_| {
+            self.mailboxes.insert(username.to_string(), removed);
+        })?;
+        Ok(count)
+    }
+
+    fn expire(&mut self, username: &str) {
+        let cutoff = now_secs().saturating_sub(self.max_age.as_secs());
+        if let Some(mailbox) = self.mailboxes.get_mut(username) {
+            mailbox.retain(|mail| mail.sent_at >= cutoff);
+            if mailbox.is_empty() {
+                self.mailboxes.remove(username);
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(&self.mailboxes)
+            .map_err(|e| format!("Failed to serialize mail: {}", e))?;
+
+        let temp_file = format!("{}.tmp", self.file_path);
+        fs::write(&temp_file, json)
+            .map_err(|e| format!("Failed to write temporary mail file: {}", e))?;
+        fs::rename(&temp_file, &self.file_path)
+            .map_err(|e| format!("Failed to rename mail file: {}", e))?;
+
+        Ok(())
+    }
+}
@@ -3,45 +3,210 @@ mod user;
 mod auth;
 mod channel;
 mod voice;
+mod line_reader;
+mod config;
+mod poll;
+mod message_log;
+mod format;
+mod password;
+mod dice;
+mod webhook;
+mod mailbox;
+mod emoji;
+mod presence;
+mod broadcast;
+mod geoip;
+mod email;
 
-use crate::auth::AuthManager;
+use crate::auth::{AuthManager, RegistrationEmail, UsernameRules};
+use crate::broadcast::{Admission, BroadcastLimiter, SenderLimiter};
 use crate::channel::{ChannelManager, ChannelType};
-use crate::client::Client;
+use crate::client::{Client, ClientOutput, DisconnectReason, PendingAction, Recipient};
+use crate::config::Config;
+use crate::dice::Dice;
+use crate::email::Mailer;
+use crate::geoip::GeoIp;
+use crate::line_reader::{Line, LineReader};
+use crate::mailbox::{Mail, MailboxManager};
+use crate::message_log::MessageLogger;
+use crate::poll::PollManager;
+use crate::user::Role;
+use crate::presence::PresenceRoster;
 use crate::voice::VoiceChannelManager;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex, mpsc};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock, mpsc};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use socket2::{Domain, Socket, Type};
 use uuid::Uuid;
 
 const MAX_CONNECTIONS: usize = 100;
 const READ_TIMEOUT: Duration = Duration::from_secs(30);
-const BUFFER_SIZE: usize = 4096;
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_WRITE_FAILURES: u32 = 5;
+const MAX_PURGE_COUNT: usize = 500;
+const PURGE_CONFIRM_THRESHOLD: usize = 50;
+const MAX_WEBHOOK_TEXT_LENGTH: usize = 2000;
+const WEBHOOK_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_SEARCH_RESULTS: usize = 20;
+const COUNT_SUMMARY_SIZE: usize = 10;
+const MAX_SUGGESTIONS: usize = 5;
+const DEFAULT_LAST_COMMANDS: usize = 10;
+/// Characters of a channel's latest line shown by `/channels --preview`.
+const PREVIEW_LENGTH: usize = 40;
+const MAX_SILENCE_MINUTES: u64 = 1440;
+const BANNER_FILE: &str = "banner.txt";
+const MAX_BANNER_SIZE: u64 = 4096;
+const EXPORT_MESSAGE_LIMIT: usize = 100;
+/// Built-in channel only moderators and admins can see or join.
+const STAFF_CHANNEL: &str = "staff";
+/// Exit status asking a supervisor to start the server again (`EX_TEMPFAIL`).
+const RESTART_EXIT_CODE: i32 = 75;
+const DEFAULT_SHUTDOWN_DELAY_SECS: u64 = 10;
+const MAX_SHUTDOWN_DELAY_SECS: u64 = 600;
+const SHUTDOWN_FLUSH_DELAY: Duration = Duration::from_millis(500);
+/// Longest a closing connection waits for the peer to read its final lines.
+const CLOSE_LINGER: Duration = Duration::from_secs(2);
+const LOG_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const HELP_MESSAGE: &str = "\n=== Commands ===\n\
+                            /channels [--preview] - List all channels, optionally with each one's latest message\n\
+                            /join <channel> - Join a text channel\n\
+                            /voice <channel> - Join a voice channel\n\
+                            /voicedefaults [muted|unmuted|deafened] - Show or set how you join voice channels\n\
+                            /voicelimit <channel> <n> - Set a voice channel's participant cap (moderators)\n\
+                            /leave - Leave current voice channel\n\
+                            /create <name> text|voice - Create a new channel\n\
+                            /verify <code>|resend - Verify your email address\n\
+                            /users - List users in current channel\n\
+                            /search <term> - Find channels by name or welcome message\n\
+                            /suggest <interest> - Recommend up to 5 open channels for an interest\n\
+                            /channelinfo [channel] - Show details about a channel\n\
+                            /count [channel] - Show who has sent the most messages in a channel\n\
+                            /invisible on|off - Hide yourself from user lists and join/leave notices\n\
+                            /setname [display name] - Set the name shown in chat and /users (no argument clears it)\n\
+                            /receipts on|off - Confirm whether your direct messages were delivered (default on)\n\
+                            /emoji on|off - Show :shortcodes: like :smile: as emoji (default on)\n\
+                            /markdown on|off - Render *bold*, _italic_ and `code` in messages (needs an ANSI terminal)\n\
+                            /colorscheme [name] - List or pick a color scheme for received messages\n\
+                            /roll <NdM> - Roll dice in the current channel, e.g. /roll 2d6\n\
+                            /mentionsonly on|off - Only show messages that @mention you in the current channel\n\
+                            /subscribe presence - Receive the online roster, then join/leave/connect events (/unsubscribe to stop)\n\
+                            /msg <user> <message> - Send a direct message\n\
+                            /mail <user> <message> - Leave a message for an offline user\n\
+                            /mail - Re-read your stored messages; /mailclear deletes them\n\
+                            /r <message> - Reply to the last person who messaged you\n\
+                            /mods - List moderators and whether they are online\n\
+                            /export - Download your account data as JSON\n\
+                            /deleteaccount - Permanently delete your account\n\
+                            /autojoin on|off - Rejoin your channels on next login\n\
+                            /autoafk on|off - Toggle automatic AFK marking when idle\n\
+                            /announcemode on|off - Make the current channel read-only for non-moderators (moderators)\n\
+                            /logging on|off - Toggle message logging for this channel (moderators)\n\
+                            /say <channel> <message> - Post a server notice to a channel (moderators)\n\
+                            /clearhistory --disk - Delete the current channel's whole message log (moderators)\n\
+                            /purge <count> - Delete recent messages from the current channel's log (moderators)\n\
+                            /delete <channel> - Delete a channel, moving its members to the default channel (moderators)\n\
+                            /hide <channel> - Unlist and stop saving a channel; it is removed once empty (moderators)\n\
+                            /lock [channel] / /unlock [channel] - Stop or allow new joins to a channel (moderators)\n\
+                            /evacuate <channel> [--lock] - Move everyone in a channel to the default channel (moderators)\n\
+                            /welcome <text>|clear - Set the current channel's join greeting (moderators)\n\
+                            /summon <user> - Notify a user, via webhook if they are offline (moderators)\n\
+                            /silence <user> <minutes> - Stop a user from chatting for a while (moderators)\n\
+                            /unsilence <user> - Lift a silence early (moderators)\n\
+                            /whois <user> - Show account, silence and connection details (moderators)\n\
+                            /listsilences - Show silenced users and time left (moderators)\n\
+                            /spectate <channel> - Watch a channel without joining it (moderators)\n\
+                            /unspectate - Stop spectating\n\
+                            /poll <question> | <opt1> | <opt2> ... - Start a poll\n\
+                            /vote <poll_id> <option> - Vote in a poll\n\
+                            /pollresults <poll_id> - Show poll results\n\
+                            /pollclose <poll_id> - Close a poll you started\n\
+                            /more - Show the next page of a long response\n\
+                            /last [n] - Show the commands you last typed (default 10, up to 20)\n\
+                            /whereami - Show which channel you're talking in\n\
+                            /motd - Show the message of the day again\n\
+                            /serverinfo - Show server version, uptime and activity\n\
+                            /connections - List connections with their IDs (admins)\n\
+                            /disconnect <id> - Close a connection by ID (admins)\n\
+                            /shutdown [seconds] - Stop the server after a countdown (admins)\n\
+                            /restart [seconds] - Restart the server after a countdown (admins)\n\
+                            /help - Show this help message\n\
+                            /quit - Exit chat\n\
+                            ================\n\n";
+
+const PRE_AUTH_HELP_MESSAGE: &str = "Log in or register first. Until then these commands work:\n\
+                                     /help - Show this message\n\
+                                     /version - Show the server version\n\
+                                     /quit - Disconnect\n";
 
 struct Server {
+    config: Config,
     clients: Arc<Mutex<HashMap<Uuid, Client>>>,
     auth_manager: Arc<Mutex<AuthManager>>,
-    channel_manager: Arc<Mutex<ChannelManager>>,
+    channel_manager: Arc<RwLock<ChannelManager>>,
     voice_manager: Arc<Mutex<VoiceChannelManager>>,
-    shutdown_tx: mpsc::Sender<()>,
+    poll_manager: Arc<Mutex<PollManager>>,
+    message_logger: Arc<Mutex<MessageLogger>>,
+    mailbox_manager: Arc<Mutex<MailboxManager>>,
+    /// Silenced usernames and when their silence expires.
+    silences: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Lock order: `presence` before `clients`.
+    presence: Arc<Mutex<PresenceRoster>>,
+    broadcast_limiter: Arc<Mutex<BroadcastLimiter>>,
+    sender_limiter: Arc<Mutex<SenderLimiter>>,
+    /// Carries the exit code to the main thread, which performs the shutdown.
+    shutdown_tx: mpsc::Sender<i32>,
     connection_count: Arc<Mutex<usize>>,
+    ip_connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    started_at: Instant,
+    /// Set once `/shutdown` or `/restart` has started its countdown.
+    shutdown_scheduled: Mutex<bool>,
+    geoip: GeoIp,
+    mailer: Mailer,
 }
 
 impl Server {
-    fn new() -> (Self, mpsc::Receiver<()>) {
-        let channel_manager = ChannelManager::new(); // Now loads channels automatically
+    fn new() -> (Self, mpsc::Receiver<i32>) {
+        let config = Config::load("config.json");
+        // Temp files sit next to their targets inside the data dir, so the rename stays atomic
+        std::fs::create_dir_all(&config.data_dir).expect("Failed to create data directory");
+
+        let mut channel_manager = ChannelManager::new_with_config(&config.data_path(&config.channels_file), &config.starter_channels);
+        channel_manager.ensure_channel(&config.default_channel, ChannelType::Text);
+        channel_manager.ensure_channel(STAFF_CHANNEL, ChannelType::Text);
+
+        let hasher = password::hasher_from_name(&config.password_hash).unwrap_or_else(|| {
+            eprintln!("Unknown password_hash {:?}, using bcrypt", config.password_hash);
+            Box::new(password::BcryptHasher)
+        });
 
         let (shutdown_tx, shutdown_rx) = mpsc::channel();
 
         let server = Server {
+            poll_manager: Arc::new(Mutex::new(PollManager::new(config.poll_duration()))),
+            message_logger: Arc::new(Mutex::new(MessageLogger::new(config.log_messages, &config.data_path(&config.log_dir), config.log_max_bytes))),
+            mailbox_manager: Arc::new(Mutex::new(MailboxManager::new(&config.data_path(&config.mail_file), config.mail_expiry()))),
+            auth_manager: Arc::new(Mutex::new(AuthManager::new(&config.data_path(&config.users_file), hasher, config.max_accounts,
+                UsernameRules::new(&config.reserved_usernames, &config.banned_usernames)))),
+            broadcast_limiter: Arc::new(Mutex::new(BroadcastLimiter::new(config.broadcast_budget_per_sec))),
+            sender_limiter: Arc::new(Mutex::new(SenderLimiter::new(config.rate_limits.clone()))),
+            geoip: GeoIp::open(config.geoip_database.as_deref()),
+            mailer: Mailer::open(config.smtp_url.as_deref(), config.smtp_from.as_deref()),
+            config,
             clients: Arc::new(Mutex::new(HashMap::new())),
-            auth_manager: Arc::new(Mutex::new(AuthManager::new("users.json"))),
-            channel_manager: Arc::new(Mutex::new(channel_manager)),
+            channel_manager: Arc::new(RwLock::new(channel_manager)),
             voice_manager: Arc::new(Mutex::new(VoiceChannelManager::new())),
+            silences: Arc::new(Mutex::new(HashMap::new())),
+            presence: Arc::new(Mutex::new(PresenceRoster::new())),
             shutdown_tx,
             connection_count: Arc::new(Mutex::new(0)),
+            ip_connections: Arc::new(Mutex::new(HashMap::new())),
+            started_at: Instant::now(),
+            shutdown_scheduled: Mutex::new(false),
         };
 
         (server, shutdown_rx)
@@ -73,25 +238,87 @@ impl Server {
             *count = count.saturating_sub(1);
         }
     }
+
+    fn increment_ip_connection_count(&self, ip: IpAddr) -> bool {
+        match self.ip_connections.lock() {
+            Ok(mut connections) => {
+                let count = connections.entry(ip).or_insert(0);
+                if *count < self.config.max_connections_per_ip {
+                    *count += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn decrement_ip_connection_count(&self, ip: IpAddr) {
+        if let Ok(mut connections) = self.ip_connections.lock()
+            && let Some(count) = connections.get_mut(&ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                connections.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Holds one global and one per-IP connection slot, releasing both when the
+/// connection's thread finishes, however it finishes.
+struct ConnectionGuard {
+    server: Arc<Server>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.server.decrement_connection_count();
+        self.server.decrement_ip_connection_count(self.ip);
+    }
 }
 
 type ServerResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 fn handle_client(mut stream: TcpStream, server: Arc<Server>) -> ServerResult<()> {
-    // Set read timeout
+    // The read timeout doubles as the interval for idle and AFK checks
     stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    // Bound how long a write to a stalled client can block a broadcast
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
     
-    let authenticated_user = match authenticate_client(&mut stream, &server.auth_manager) {
+    let mut reader = LineReader::new();
+
+    let authenticated_user = match authenticate_client(&mut stream, &mut reader, &server) {
         Ok(user) => user,
         Err(e) => {
-            let _ = stream.write_all(format!("Authentication failed: {}\n", e).as_bytes());
+            if is_disconnect(e.as_ref()) {
+                println!("Client disconnected during authentication");
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            } else {
+                let (message, reason) = if is_timeout(e.as_ref()) {
+                    (server.config.login_timeout_message.clone(), DisconnectReason::LoginTimeout)
+                } else {
+                    (format!("Authentication failed: {}", e), DisconnectReason::AuthFailed)
+                };
+                let _ = stream.write_all(format!("{}\n{}", message, reason.line()).as_bytes());
+                close_gracefully(&mut stream);
+            }
             return Ok(());
         }
     };
 
     println!("User {} authenticated successfully", authenticated_user.name);
 
-    let client = match Client::new(stream.try_clone()?, authenticated_user) {
+    let first_login = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock".to_string())
+        .and_then(|mut auth| auth.record_login(&authenticated_user.name))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to record login for {}: {}", authenticated_user.name, e);
+            false
+        });
+
+    let mut client = match Client::new(stream.try_clone()?, authenticated_user, &server.config.default_channel) {
         Ok(client) => client,
         Err(e) => {
             let _ = stream.write_all(b"Failed to create client session\n");
@@ -101,15 +328,30 @@ fn handle_client(mut stream: TcpStream, server: Arc<Server>) -> ServerResult<()>
 
     let client_id = client.id;
 
+    client.start_writer(server.config.batch_window().unwrap_or_default(), server.config.max_output_queue_kb * 1024)?;
+    // Everything from here on is written through the writer thread, in order with broadcasts
+    let mut output = client.output()?;
+
     // Show available channels
-    if let Err(e) = show_channels(&mut stream, &server.channel_manager) {
+    if let Err(e) = show_channels(&mut output, &server, &client.user.name) {
         eprintln!("Failed to show channels to client: {}", e);
     }
 
-    // Join general channel
-    if let Ok(mut channel_manager) = server.channel_manager.lock() {
-        channel_manager.join_channel("general", client.user.name.clone());
+    // Join the default channel, or the saved channels if auto-rejoin is enabled
+    let (channels_to_join, active_channel) = initial_channels(&server, &client.user.name);
+    if let Ok(channel_manager) = server.channel_manager.read() {
+        for channel in &channels_to_join {
+            channel_manager.join_channel(channel, client.user.name.clone());
+        }
     }
+    client.current_channel = Some(active_channel.clone());
+    let prefs = server.auth_manager.lock()
+        .map(|auth| auth.get_prefs(&client.user.name))
+        .unwrap_or_default();
+    client.markdown = prefs.markdown;
+    client.color_scheme = prefs.color_scheme;
+    client.emoji = prefs.emoji;
+    client.display_name = prefs.display_name;
 
     // Add client to server
     if let Ok(mut clients_guard) = server.clients.lock() {
@@ -117,362 +359,3395 @@ fn handle_client(mut stream: TcpStream, server: Arc<Server>) -> ServerResult<()>
     }
 
     // Broadcast join message
-    broadcast_to_channel(
-        &server.clients,
-        &server.channel_manager,
-        "general",
-        &format!("*** {} joined the channel ***\n", client.user.name),
-        Some(client_id),
-    );
+    publish_presence(&server, "connect", &client.user.name, None, false);
+    for channel in &channels_to_join {
+        broadcast_to_channel(
+            &server.clients,
+            &server.channel_manager,
+            channel,
+            &format!("*** {} joined the channel ***\n", client.user.name),
+            Some(client_id),
+        );
+        publish_presence(&server, "join", &client.user.name, Some(channel), false);
+    }
+
+    if active_channel != server.config.default_channel {
+        let _ = output.write_all(format!("Rejoined channel: {}\n", active_channel).as_bytes());
+    }
+    let _ = send_channel_welcome(&mut output, &server, &active_channel);
+
+    let resumed_voice = server.voice_manager.lock().ok()
+        .and_then(|mut voice_manager| voice_manager.resume(&client.user.name, client_id));
+    if let Some(voice_channel) = resumed_voice {
+        let _ = output.write_all(format!("Rejoined voice channel: {}\n", voice_channel).as_bytes());
+    }
 
     // Send help message
-    let help_msg = "\n=== Commands ===\n\
-                   /channels - List all channels\n\
-                   /join <channel> - Join a text channel\n\
-                   /voice <channel> - Join a voice channel\n\
-                   /leave - Leave current voice channel\n\
-                   /create <name> text|voice - Create a new channel\n\
-                   /users - List users in current channel\n\
-                   /help - Show this help message\n\
-                   /quit - Exit chat\n\
-                   ================\n\n";
-    let _ = stream.write_all(help_msg.as_bytes());
-
-    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let _ = output.write_all(with_command_prefix(HELP_MESSAGE, server.config.command_prefix()).as_bytes());
+
+    if first_login && !server.config.onboarding_message.is_empty() {
+        let onboarding = format!("*** Welcome to {}, {}! ***\n{}\n",
+                                 server.config.server_name, client.user.name, server.config.onboarding_message);
+        let _ = output.write_all(with_command_prefix(&onboarding, server.config.command_prefix()).as_bytes());
+    }
+
+    let mail = server.mailbox_manager.lock()
+        .map(|mut mailboxes| mailboxes.messages(&client.user.name))
+        .unwrap_or_default();
+    if !mail.is_empty() {
+        let _ = output.write_all(format_mail(&mail, server.config.command_prefix()).as_bytes());
+    }
 
+    let mut disconnect_reason = None;
     loop {
-        match stream.read(&mut buffer) {
-            Ok(0) => break, // Client disconnected
-            Ok(n) => {
-                let message = String::from_utf8_lossy(&buffer[..n]).trim().to_string();
+        match reader.read_line(&mut stream) {
+            Ok(None) => break, // Client disconnected
+            Ok(Some(Line::TooLong)) => {
+                let _ = output.write_all(b"Input too long\n");
+            }
+            Ok(Some(Line::Invalid)) => {
+                let _ = output.write_all(b"Invalid input\n");
+            }
+            Ok(Some(Line::InvalidUtf8)) => {
+                let _ = output.write_all(b"Invalid UTF-8 in input\n");
+            }
+            Ok(Some(Line::Complete(line))) => {
+                let message = line.trim().to_string();
+                if message.is_empty() {
+                    continue;
+                }
 
-                if message == "/quit" {
-                    let _ = stream.write_all(b"Goodbye!\n");
-                    break;
+                if touch_client(&server.clients, client_id) {
+                    announce_afk_change(&server, client_id, &client.user.name, false);
                 }
 
-                if message.starts_with('/') {
-                    if let Err(e) = handle_command(&mut stream, &server, &message, &client.user.name, client_id) {
-                        eprintln!("Command handling error: {}", e);
-                        let _ = stream.write_all(b"Command failed. Please try again.\n");
-                    }
-                } else {
-                    // Handle regular message
-                    let current_channel = get_client_current_channel(&server.clients, client_id);
-                    
-                    if let Some(channel) = current_channel {
-                        let full_message = format!("[{}] {}: {}\n", channel, client.user.name, message);
-                        broadcast_to_channel(&server.clients, &server.channel_manager,
-                                             &channel, &full_message, Some(client_id));
+                // A pending prompt consumes the line before it can be treated as chat
+                if let Some(action) = take_pending_action(&server.clients, client_id) {
+                    match handle_pending_action(&mut output, &server, action, &message, &client.user.name, client_id) {
+                        Ok(true) => {
+                            disconnect_reason = Some(DisconnectReason::AccountDeleted);
+                            break;
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            eprintln!("Command handling error: {}", e);
+                            let _ = output.write_all(b"Command failed. Please try again.\n");
+                        }
                     }
+                    continue;
                 }
-            }
-            Err(e) => {
-                match e.kind() {
-                    std::io::ErrorKind::TimedOut => {
-                        let _ = stream.write_all(b"Connection timed out due to inactivity.\n");
+
+                match classify_input(&message, server.config.command_prefix()) {
+                    Input::Command(command) if command == "/quit" => {
+                        let _ = output.write_all(b"Goodbye!\n");
+                        disconnect_reason = Some(DisconnectReason::Quit);
+                        break;
+                    }
+                    Input::Command(command) => {
+                        // Prompt answers such as passwords never get here; only typed commands are kept
+                        if !matches!(command.split_whitespace().next(), Some("/last" | "/more"))
+                            && let Ok(mut clients) = server.clients.lock()
+                            && let Some(client) = clients.get_mut(&client_id) {
+                            client.record_command(&message);
+                        }
+                        if let Err(e) = handle_command(&mut output, &server, &command, &client.user.name, client_id) {
+                            eprintln!("Command handling error: {}", e);
+                            let _ = output.write_all(b"Command failed. Please try again.\n");
+                        }
                     }
-                    _ => {
-                        eprintln!("Read error from client: {}", e);
+                    Input::Chat(text) => {
+                        handle_chat_message(&mut output, &server, text, &client.user.name, client_id);
                     }
                 }
+            }
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                let idle = client_idle_time(&server.clients, client_id).unwrap_or_default();
+                if idle >= server.config.idle_timeout() {
+                    let _ = output.write_all(format!("{}\n", server.config.idle_disconnect_message).as_bytes());
+                    disconnect_reason = Some(DisconnectReason::IdleTimeout);
+                    break;
+                }
+
+                let remaining = server.config.idle_timeout() - idle;
+                if remaining <= server.config.idle_warning() && mark_idle_warned(&server.clients, client_id) {
+                    let _ = output.write_all(format!(
+                        "You will be disconnected in {}s due to inactivity — send any message to stay\n",
+                        remaining.as_secs()).as_bytes());
+                }
+
+                if idle >= server.config.afk_timeout() && mark_client_afk(&server, client_id, &client.user.name) {
+                    announce_afk_change(&server, client_id, &client.user.name, true);
+                }
+
+                if server.config.voice_idle_timeout().is_some_and(|timeout| idle >= timeout)
+                    && server.voice_manager.lock()
+                        .is_ok_and(|mut voice_manager| voice_manager.leave_voice_channel(&client.user.name, client_id)) {
+                    let _ = output.write_all(b"Removed from voice for inactivity\n");
+                }
+            }
+            Err(e) => {
+                eprintln!("Read error from client: {}", e);
                 break;
             }
         }
     }
 
+    if let Some(reason) = disconnect_reason {
+        let _ = output.write_all(reason.line().as_bytes());
+    }
+
     // Cleanup client
     cleanup_client(&server, client_id, &client.user.name);
-    server.decrement_connection_count();
-    
+    if disconnect_reason.is_some() {
+        let _ = output.flush();
+        close_gracefully(&mut stream);
+    }
+
     println!("User {} disconnected", client.user.name);
     Ok(())
 }
 
+enum Input<'a> {
+    /// A command rewritten to use `/`, whatever the configured prefix is.
+    Command(Cow<'a, str>),
+    Chat(&'a str),
+}
+
+/// Splits input into commands and chat by the configured prefix. A doubled
+/// prefix escapes it, so `//shrug` is sent as the chat line `/shrug`.
+fn classify_input<'a>(message: &'a str, prefix: &str) -> Input<'a> {
+    let Some(rest) = message.strip_prefix(prefix) else {
+        return Input::Chat(message);
+    };
+    if rest.starts_with(prefix) {
+        return Input::Chat(rest);
+    }
+    if prefix == "/" {
+        Input::Command(Cow::Borrowed(message))
+    } else {
+        Input::Command(Cow::Owned(format!("/{}", rest)))
+    }
+}
+
+/// Writes a reply that names commands, such as a usage line, spelling them
+/// with the configured prefix.
+fn write_hint(stream: &mut ClientOutput, server: &Arc<Server>, text: &str) -> std::io::Result<()> {
+    stream.write_all(with_command_prefix(text, server.config.command_prefix()).as_bytes())
+}
+
+/// Rewrites `/command` references in user-facing text to the configured prefix.
+fn with_command_prefix<'a>(text: &'a str, prefix: &str) -> Cow<'a, str> {
+    if prefix == "/" {
+        return Cow::Borrowed(text);
+    }
+
+    let mut output = String::with_capacity(text.len());
+    let mut previous = None;
+    for (i, c) in text.char_indices() {
+        let starts_command = c == '/'
+            && previous.is_none_or(|p: char| p.is_whitespace() || p == '(')
+            && text[i + 1..].starts_with(|next: char| next.is_ascii_lowercase());
+        if starts_command {
+            output.push_str(prefix);
+        } else {
+            output.push(c);
+        }
+        previous = Some(c);
+    }
+    Cow::Owned(output)
+}
+
 fn get_client_current_channel(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client_id: Uuid) -> Option<String> {
     clients.lock().ok()?
         .get(&client_id)?
         .current_channel.clone()
 }
 
-fn cleanup_client(server: &Arc<Server>, client_id: Uuid, username: &str) {
-    // Get current channel before removing client
-    let current_channel = get_client_current_channel(&server.clients, client_id);
+/// Records input from the client and returns whether it was marked AFK until now.
+fn touch_client(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client_id: Uuid) -> bool {
+    if let Ok(mut clients_guard) = clients.lock()
+        && let Some(client) = clients_guard.get_mut(&client_id) {
+        client.last_activity = Instant::now();
+        client.idle_warned = false;
+        return std::mem::replace(&mut client.afk, false);
+    }
+    false
+}
 
-    // Remove client from clients list
-    if let Ok(mut clients_guard) = server.clients.lock() {
-        clients_guard.remove(&client_id);
+fn client_idle_time(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client_id: Uuid) -> Option<Duration> {
+    clients.lock().ok()?
+        .get(&client_id)
+        .map(|client| client.last_activity.elapsed())
+}
+
+/// Returns `true` the first time it is called since the client's last activity.
+fn mark_idle_warned(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client_id: Uuid) -> bool {
+    clients.lock().ok()
+        .and_then(|mut clients_guard| clients_guard.get_mut(&client_id)
+            .map(|client| !std::mem::replace(&mut client.idle_warned, true)))
+        .unwrap_or(false)
+}
+
+/// Marks the client AFK unless the user opted out, returning whether the state changed.
+fn mark_client_afk(server: &Arc<Server>, client_id: Uuid, username: &str) -> bool {
+    let auto_afk = server.auth_manager.lock()
+        .map(|auth| auth.get_prefs(username).auto_afk)
+        .unwrap_or(false);
+    if !auto_afk {
+        return false;
     }
 
-    // Leave all channels
-    if let Ok(mut channel_manager) = server.channel_manager.lock() {
-        channel_manager.leave_all_channels(username);
+    if let Ok(mut clients_guard) = server.clients.lock()
+        && let Some(client) = clients_guard.get_mut(&client_id)
+        && !client.afk {
+        client.afk = true;
+        return true;
     }
+    false
+}
 
-    // Leave voice channels
-    if let Ok(mut voice_manager) = server.voice_manager.lock() {
-        voice_manager.leave_voice_channel(username);
+fn announce_afk_change(server: &Arc<Server>, client_id: Uuid, username: &str, afk: bool) {
+    if is_client_invisible(&server.clients, client_id) {
+        return;
     }
 
-    // Broadcast leave message
-    if let Some(channel) = current_channel {
-        broadcast_to_channel(&server.clients, &server.channel_manager,
-                             &channel,
-                             &format!("*** {} left the channel ***\n", username),
-                             None);
+    if let Some(channel) = get_client_current_channel(&server.clients, client_id) {
+        let message = if afk {
+            format!("*** {} is now AFK ***\n", username)
+        } else {
+            format!("*** {} is back ***\n", username)
+        };
+        broadcast_to_channel(&server.clients, &server.channel_manager, &channel, &message, None);
     }
 }
 
-fn handle_command(stream: &mut TcpStream, server: &Arc<Server>, command: &str, username: &str, client_id: Uuid) -> ServerResult<()> {
-    let parts: Vec<&str> = command.split_whitespace().collect();
-    if parts.is_empty() {
-        return Ok(());
+fn set_pending_action(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client_id: Uuid, action: PendingAction) {
+    if let Ok(mut clients_guard) = clients.lock()
+        && let Some(client) = clients_guard.get_mut(&client_id) {
+        client.pending_action = Some(action);
     }
+}
 
-    match parts[0] {
-        "/help" => {
-            let help_msg = "\n=== Commands ===\n\
-                           /channels - List all channels\n\
-                           /join <channel> - Join a text channel\n\
-                           /voice <channel> - Join a voice channel\n\
-                           /leave - Leave current voice channel\n\
-                           /create <name> text|voice - Create a new channel\n\
-                           /users - List users in current channel\n\
-                           /help - Show this help message\n\
-                           /quit - Exit chat\n\
-                           ================\n\n";
-            stream.write_all(help_msg.as_bytes())?;
-        }
-        "/channels" => {
-            show_channels(stream, &server.channel_manager)?;
-        }
-        "/join" => {
-            handle_join_command(stream, server, &parts, username, client_id)?;
-        }
-        "/voice" => {
-            handle_voice_command(stream, server, &parts, username)?;
-        }
-        "/leave" => {
-            handle_leave_command(stream, server, username)?;
-        }
-        "/create" => {
-            handle_create_command(stream, server, &parts)?;
+fn take_pending_action(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client_id: Uuid) -> Option<PendingAction> {
+    clients.lock().ok()?
+        .get_mut(&client_id)?
+        .pending_action.take()
+}
+
+/// Completes a prompt started by an earlier command. Returns `true` when the
+/// client should be disconnected.
+fn handle_pending_action(stream: &mut ClientOutput, server: &Arc<Server>, action: PendingAction, input: &str, username: &str, client_id: Uuid) -> ServerResult<bool> {
+    if input.strip_prefix(server.config.command_prefix()) == Some("cancel") {
+        stream.write_all(b"Cancelled\n")?;
+        return Ok(false);
+    }
+
+    match action {
+        PendingAction::DeleteAccount => {
+            let result = server.auth_manager.lock()
+                .map_err(|_| "Failed to acquire auth manager lock")?
+                .delete_user(username, input);
+
+            match result {
+                Ok(()) => {
+                    println!("User {} deleted their account", username);
+                    stream.write_all(b"Your account has been deleted. Goodbye!\n")?;
+                    disconnect_other_sessions(server, username, client_id);
+                    Ok(true)
+                }
+                Err(e) => {
+                    stream.write_all(format!("Account not deleted: {}\n", e).as_bytes())?;
+                    Ok(false)
+                }
+            }
         }
-        "/users" => {
-            handle_users_command(stream, server, client_id)?;
+        PendingAction::Purge { channel, count } => {
+            if input.eq_ignore_ascii_case("yes") {
+                purge_channel(stream, server, &channel, count, username)?;
+            } else {
+                stream.write_all(b"Purge cancelled\n")?;
+            }
+            Ok(false)
         }
-        _ => {
-            stream.write_all(b"Unknown command. Type /help for available commands.\n")?;
+        PendingAction::ClearHistory { channel } => {
+            if input.eq_ignore_ascii_case("yes") {
+                clear_channel_history(server, &channel, username)?;
+            } else {
+                stream.write_all(b"Clear cancelled\n")?;
+            }
+            Ok(false)
         }
     }
-    Ok(())
 }
 
-fn handle_join_command(stream: &mut TcpStream, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
-    if parts.len() < 2 {
-        stream.write_all(b"Usage: /join <channel_name>\n")?;
-        return Ok(());
+/// Closes every other connection logged in as `username`; their threads then clean up normally.
+fn disconnect_other_sessions(server: &Arc<Server>, username: &str, client_id: Uuid) {
+    let sessions: Vec<Client> = server.clients.lock()
+        .map(|clients_guard| clients_guard.values()
+            .filter(|client| client.user.name == username && client.id != client_id)
+            .filter_map(|client| client.try_clone().ok())
+            .collect())
+        .unwrap_or_default();
+    for mut session in sessions {
+        disconnect_client(&mut session, DisconnectReason::AccountDeleted);
     }
+}
 
-    let channel_name = parts[1];
-    let mut channel_manager = server.channel_manager.lock().map_err(|_| "Failed to acquire channel manager lock")?;
+/// Queues the reason line behind whatever the client is still owed, then
+/// ends its read loop; its writer delivers the queue before the socket closes.
+fn disconnect_client(client: &mut Client, reason: DisconnectReason) {
+    let _ = client.send(reason.line().as_bytes());
+    let _ = client.stream.shutdown(std::net::Shutdown::Read);
+}
 
-    if !channel_manager.channel_exists(channel_name) {
-        stream.write_all(b"Channel does not exist\n")?;
-        return Ok(());
-    }
+/// Control characters (ANSI escapes in particular) could reconfigure other users' terminals.
+fn contains_control_chars(message: &str) -> bool {
+    message.chars().any(|c| c.is_control() && c != '\t')
+}
 
-    // Get old channel
-    let old_channel = get_client_current_channel(&server.clients, client_id);
+fn handle_chat_message(stream: &mut ClientOutput, server: &Arc<Server>, message: &str, username: &str, client_id: Uuid) {
+    if contains_control_chars(message) {
+        let _ = stream.write_all(b"Message contains invalid characters\n");
+        return;
+    }
 
-    // Leave old channel
-    if let Some(old) = &old_channel {
-        channel_manager.leave_channel(old, username);
-        broadcast_to_channel(&server.clients, &server.channel_manager,
-                             old,
-                             &format!("*** {} left the channel ***\n", username),
-                             None);
+    let dedupe_window = server.config.dedupe_window();
+    if let Some(window) = dedupe_window {
+        let duplicate = server.clients.lock()
+            .ok()
+            .and_then(|mut clients| clients.get_mut(&client_id)
+                .and_then(|client| {
+                    let channel = client.current_channel.clone()?;
+                    Some(client.recent_messages.is_duplicate(&channel, message, window))
+                }))
+            .unwrap_or(false);
+        if duplicate {
+            return;
+        }
     }
 
-    // Join new channel
-    channel_manager.join_channel(channel_name, username.to_string());
+    let Some(post) = admit_post(stream, server, username, client_id, |_| message.to_string()) else {
+        return;
+    };
 
-    // Update client's current channel
-    if let Ok(mut clients) = server.clients.lock() {
-        if let Some(client) = clients.get_mut(&client_id) {
-            client.current_channel = Some(channel_name.to_string());
-        }
+    let sender = display_name(&server.clients, client_id).unwrap_or_else(|| username.to_string());
+    broadcast_chat_message(&server.clients, &server.channel_manager,
+                           &post.channel, &sender, message, Some(client_id));
+    log_channel_message(server, &post.channel, username, message);
+    if let Ok(manager) = server.channel_manager.read() {
+        manager.count_message(&post.channel, username);
+        manager.record_last_message(&post.channel, message);
+    }
+    // Only delivered lines are remembered, so a throttled retry still goes out
+    if dedupe_window.is_some()
+        && let Ok(mut clients) = server.clients.lock()
+        && let Some(client) = clients.get_mut(&client_id) {
+        client.recent_messages.record(&post.channel, message);
     }
 
-    stream.write_all(format!("Joined channel: {}\n", channel_name).as_bytes())?;
-    broadcast_to_channel(&server.clients, &server.channel_manager,
-                         channel_name,
-                         &format!("*** {} joined the channel ***\n", username),
-                         Some(client_id));
+    announce_slow_mode_change(server, &post);
+}
 
-    Ok(())
+/// A line cleared by `admit_post` to go out to `channel`.
+struct Post {
+    channel: String,
+    text: String,
+    /// The channel's new slow-mode state, if this line changed it.
+    slow_mode_change: Option<bool>,
 }
 
-fn handle_voice_command(stream: &mut TcpStream, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
-    if parts.len() < 2 {
-        stream.write_all(b"Usage: /voice <channel_name>\n")?;
-        return Ok(());
+/// Runs everything a user's line into their current channel must pass, in
+/// order: may they speak there, their own rate limit, the channel's slow
+/// mode and the server-wide broadcast budget. `render` builds the line for
+/// the channel. Only an admitted line counts towards slow mode.
+fn admit_post(stream: &mut ClientOutput, server: &Arc<Server>, username: &str, client_id: Uuid,
+              render: impl FnOnce(&str) -> String) -> Option<Post> {
+    let channel = admit_speaker(stream, server, username, client_id)?;
+    if !admit_sender(stream, server, username) {
+        return None;
     }
 
-    let channel_name = parts[1];
-    let channel_manager = server.channel_manager.lock().map_err(|_| "Failed to acquire channel manager lock")?;
+    let slow_mode_wait = server.channel_manager.read()
+        .ok()
+        .and_then(|manager| manager.slow_mode_wait(&channel, username));
+    if let Some(wait) = slow_mode_wait {
+        let _ = stream.write_all(format!("Slow mode is on; you can speak again in {} seconds\n", wait.as_millis().div_ceil(1000)).as_bytes());
+        return None;
+    }
 
-    if let Some(channel) = channel_manager.get_channel(channel_name) {
-        if channel.channel_type == ChannelType::Voice {
-            let mut voice_manager = server.voice_manager.lock().map_err(|_| "Failed to acquire voice manager lock")?;
-            voice_manager.join_voice_channel(username.to_string(), channel_name.to_string());
-            stream.write_all(format!("Joined voice channel: {}\n", channel_name).as_bytes())?;
-            stream.write_all(b"Note: Voice streaming not implemented. This is a placeholder.\n")?;
-        } else {
-            stream.write_all(b"That's not a voice channel\n")?;
-        }
-    } else {
-        stream.write_all(b"Voice channel does not exist\n")?;
+    let text = render(&channel);
+    if !admit_broadcast(stream, server, &channel, &text) {
+        return None;
     }
 
-    Ok(())
+    let slow_mode_change = server.channel_manager.read()
+        .ok()
+        .and_then(|manager| manager.record_message(&channel, username, server.config.auto_slow_mode()));
+    Some(Post { channel, text, slow_mode_change })
 }
 
-fn handle_leave_command(stream: &mut TcpStream, server: &Arc<Server>, username: &str) -> ServerResult<()> {
-    let mut voice_manager = server.voice_manager.lock().map_err(|_| "Failed to acquire voice manager lock")?;
-    if voice_manager.leave_voice_channel(username) {
-        stream.write_all(b"Left voice channel\n")?;
-    } else {
-        stream.write_all(b"You're not in a voice channel\n")?;
+/// Tells the channel when a post switched automatic slow mode on or off.
+fn announce_slow_mode_change(server: &Arc<Server>, post: &Post) {
+    match post.slow_mode_change {
+        Some(true) => broadcast_to_channel(&server.clients, &server.channel_manager, &post.channel,
+            &format!("*** Slow mode enabled due to high traffic: one message every {} seconds ***\n", server.config.slow_mode_delay_secs),
+            None),
+        Some(false) => broadcast_to_channel(&server.clients, &server.channel_manager, &post.channel,
+            "*** Slow mode disabled ***\n", None),
+        None => {}
     }
-    Ok(())
 }
 
-fn handle_create_command(stream: &mut TcpStream, server: &Arc<Server>, parts: &[&str]) -> ServerResult<()> {
-    if parts.len() < 3 {
-        stream.write_all(b"Usage: /create <name> text|voice\n")?;
-        return Ok(());
+/// Checks that the user may speak in their current channel at all: not
+/// spectating, not silenced and not in a read-only channel. Returns the
+/// channel, or tells the user why not.
+fn admit_speaker(stream: &mut ClientOutput, server: &Arc<Server>, username: &str, client_id: Uuid) -> Option<String> {
+    let spectating = server.channel_manager.read()
+        .map(|manager| manager.spectated_channel(client_id).is_some())
+        .unwrap_or(false);
+    if spectating {
+        let _ = stream.write_all(b"You are spectating; you cannot speak here\n");
+        return None;
     }
 
-    let channel_name = parts[1];
-    let channel_type = match parts[2] {
-        "text" => ChannelType::Text,
-        "voice" => ChannelType::Voice,
-        _ => {
-            stream.write_all(b"Channel type must be 'text' or 'voice'\n")?;
-            return Ok(());
-        }
+    if let Some(remaining) = silence_remaining(server, username) {
+        let minutes = remaining.as_secs().div_ceil(60);
+        let _ = stream.write_all(format!("You are silenced for another {} minutes\n", minutes).as_bytes());
+        return None;
+    }
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        let _ = stream.write_all(b"You're not in any channel\n");
+        return None;
     };
 
-    let mut channel_manager = server.channel_manager.lock().map_err(|_| "Failed to acquire channel manager lock")?;
-    if channel_manager.create_channel(channel_name, channel_type) {
-        stream.write_all(format!("Created {} channel: {}\n", parts[2], channel_name).as_bytes())?;
-    } else {
-        stream.write_all(b"Channel already exists\n")?;
+    let read_only = server.channel_manager.read()
+        .map(|manager| manager.get_channel(&channel).is_some_and(|ch| ch.announcement))
+        .unwrap_or(false);
+    if read_only && !is_staff(server, username) {
+        let _ = stream.write_all(b"This channel is read-only\n");
+        return None;
     }
 
-    Ok(())
+    Some(channel)
 }
 
-fn handle_users_command(stream: &mut TcpStream, server: &Arc<Server>, client_id: Uuid) -> ServerResult<()> {
-    let current_channel = get_client_current_channel(&server.clients, client_id);
+/// Applies the per-user rate limit for the sender's role.
+fn admit_sender(stream: &mut ClientOutput, server: &Arc<Server>, username: &str) -> bool {
+    let role = server.auth_manager.lock()
+        .map(|auth| auth.get_role(username))
+        .unwrap_or_default();
+    let admitted = server.sender_limiter.lock()
+        .map(|mut limiter| limiter.admit(username, role))
+        .unwrap_or(Ok(()));
+    if let Err(wait) = admitted {
+        let _ = stream.write_all(format!("You're sending messages too fast; wait {} seconds\n", wait.as_millis().div_ceil(1000)).as_bytes());
+        return false;
+    }
+    true
+}
 
-    if let Some(channel) = current_channel {
-        let channel_manager = server.channel_manager.lock().map_err(|_| "Failed to acquire channel manager lock")?;
-        if let Some(ch) = channel_manager.get_channel(&channel) {
-            let users = ch.users.join(", ");
-            stream.write_all(format!("Users in {}: {}\n", channel, users).as_bytes())?;
+/// Applies the server-wide broadcast budget to a line about to be fanned out
+/// to `channel`.
+fn admit_broadcast(stream: &mut ClientOutput, server: &Arc<Server>, channel: &str, text: &str) -> bool {
+    match broadcast_admission(server, channel, text) {
+        Admission::Send => true,
+        Admission::Repeated => {
+            let _ = stream.write_all(b"Message dropped: the same line was just sent here several times\n");
+            false
+        }
+        Admission::OverBudget => {
+            let _ = stream.write_all(b"Server is busy; message not sent, please try again shortly\n");
+            false
         }
-    } else {
-        stream.write_all(b"You're not in any channel\n")?;
     }
-
-    Ok(())
 }
 
-fn show_channels(stream: &mut TcpStream, channel_manager: &Arc<Mutex<ChannelManager>>) -> ServerResult<()> {
-    let manager = channel_manager.lock().map_err(|_| "Failed to acquire channel manager lock")?;
-    let channels = manager.list_channels();
+/// Asks the broadcast limiter whether `text` may go out to everyone in `channel`.
+fn broadcast_admission(server: &Arc<Server>, channel: &str, text: &str) -> Admission {
+    let recipients = server.channel_manager.read()
+        .map(|manager| manager.get_channel(channel)
+            .map_or(0, |ch| ch.users.len() + ch.observers.len()))
+        .unwrap_or(0);
+    server.broadcast_limiter.lock()
+        .map(|mut limiter| limiter.admit(channel, text, recipients))
+        .unwrap_or(Admission::Send)
+}
 
-    let mut response = String::from("\n=== Available Channels ===\n");
-    for (name, channel_type, user_count) in channels {
-        let type_str = match channel_type {
-            ChannelType::Text => "📝",
-            ChannelType::Voice => "🔊",
-        };
-        response.push_str(&format!("{} {} ({} users)\n", type_str, name, user_count));
+/// Time left on a user's silence, dropping the entry once it has expired.
+fn silence_remaining(server: &Arc<Server>, username: &str) -> Option<Duration> {
+    let mut silences = server.silences.lock().ok()?;
+    let remaining = silences.get(username)?.checked_duration_since(Instant::now());
+    if remaining.is_none() {
+        silences.remove(username);
     }
-    response.push_str("========================\n");
-
-    stream.write_all(response.as_bytes())?;
-    Ok(())
+    remaining
 }
 
-fn broadcast_to_channel(clients: &Arc<Mutex<HashMap<Uuid, Client>>>,
-                        channel_manager: &Arc<Mutex<ChannelManager>>,
-                        channel_name: &str,
-                        message: &str,
-                        exclude_client_id: Option<Uuid>) {
-    // Get channel users
-    let channel_users = if let Ok(manager) = channel_manager.lock() {
-        manager.get_channel(channel_name)
-            .map(|ch| ch.users.clone())
-            .unwrap_or_default()
-    } else {
-        return;
-    };
-
-    // Get clients to broadcast to
-    let clients_to_notify: Vec<Client> = if let Ok(clients_guard) = clients.lock() {
-        clients_guard.values()
-            .filter(|client| {
-                channel_users.contains(&client.user.name) &&
-                (exclude_client_id != Some(client.id))
-            })
-            .filter_map(|client| client.try_clone().ok())
-            .collect()
-    } else {
+fn log_channel_message(server: &Arc<Server>, channel: &str, username: &str, message: &str) {
+    let logging = server.channel_manager.read()
+        .map(|manager| manager.get_channel(channel).is_some_and(|ch| ch.logging))
+        .unwrap_or(false);
+    if !logging {
         return;
-    };
+    }
 
-    // Send messages (no locks held)
-    for mut client in clients_to_notify {
-        if client.stream.write_all(message.as_bytes()).is_err() {
-            // Remove failed client
-            if let Ok(mut clients_guard) = clients.lock() {
-                clients_guard.remove(&client.id);
-            }
-        }
+    if let Ok(logger) = server.message_logger.lock()
+        && let Err(e) = logger.log_message(channel, username, message) {
+        eprintln!("Failed to log message for {}: {}", channel, e);
     }
 }
 
-fn authenticate_client(stream: &mut TcpStream, auth_manager: &Arc<Mutex<AuthManager>>) -> ServerResult<user::User> {
-    stream.write_all(b"Welcome to the chat server!\n")?;
-    stream.write_all(b"1. Login\n2. Register\nChoose option (1 or 2): ")?;
-
-    let choice = read_line(stream)?;
+fn cleanup_client(server: &Arc<Server>, client_id: Uuid, username: &str) {
+    // Get current channel and visibility before removing client
+    let current_channel = get_client_current_channel(&server.clients, client_id);
+    let invisible = is_client_invisible(&server.clients, client_id);
 
-    match choice.as_str() {
-        "1" => login_user(stream, auth_manager),
-        "2" => register_user(stream, auth_manager),
-        _ => {
-            stream.write_all(b"Invalid choice.\n")?;
-            Err("Invalid authentication choice".into())
-        }
+    // Remove client from clients list
+    if let Ok(mut clients_guard) = server.clients.lock() {
+        clients_guard.remove(&client_id);
     }
-}
 
-fn login_user(stream: &mut TcpStream, auth_manager: &Arc<Mutex<AuthManager>>) -> ServerResult<user::User> {
-    stream.write_all(b"Username: ")?;
-    let username = read_line(stream)?;
+    save_channel_membership(server, username, current_channel.as_deref());
 
-    stream.write_all(b"Password: ")?;
-    let password = read_line(stream)?;
+    // Leave all channels
+    let mut joined_channels = Vec::new();
+    if let Ok(channel_manager) = server.channel_manager.read() {
+        joined_channels = channel_manager.channels_of(username);
+        channel_manager.leave_all_channels(username);
+        channel_manager.unspectate(client_id);
+    }
 
-    let auth = auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
-    match auth.login(&username, &password) {
+    // Keep the voice session for a grace period in case the user reconnects
+    let grace = server.config.voice_reconnect_grace();
+    if let Ok(mut voice_manager) = server.voice_manager.lock() {
+        if grace.is_zero() {
+            voice_manager.leave_voice_channel(username, client_id);
+        } else if voice_manager.mark_disconnected(username, client_id) {
+            let voice_manager = Arc::clone(&server.voice_manager);
+            let username = username.to_string();
+            thread::spawn(move || {
+                thread::sleep(grace);
+                if let Ok(mut voice_manager) = voice_manager.lock()
+                    && voice_manager.expire_disconnected(&username, client_id) {
+                    println!("Voice session of {} expired after disconnect", username);
+                }
+            });
+        }
+    }
+
+    // Broadcast leave message
+    if let Some(channel) = current_channel
+        && !invisible {
+        broadcast_to_channel(&server.clients, &server.channel_manager,
+                             &channel,
+                             &format!("*** {} left the channel ***\n", username),
+                             None);
+    }
+
+    for channel in &joined_channels {
+        publish_presence(server, "leave", username, Some(channel), invisible);
+        prune_hidden_channel(server, channel);
+    }
+    publish_presence(server, "disconnect", username, None, invisible);
+}
+
+/// Deletes `channel` if it was hidden with `/hide` and its last member,
+/// spectator and voice session are gone.
+fn prune_hidden_channel(server: &Arc<Server>, channel: &str) {
+    let hidden = server.channel_manager.read()
+        .is_ok_and(|manager| manager.get_channel(channel).is_some_and(|ch| ch.hidden));
+    let voice_in_use = server.voice_manager.lock()
+        .is_ok_and(|voice_manager| !voice_manager.get_channel_users(channel).is_empty());
+    if !hidden || voice_in_use {
+        return;
+    }
+
+    if let Ok(mut manager) = server.channel_manager.write()
+        && manager.remove_if_abandoned(channel) {
+        println!("Hidden channel {} removed after its last member left", channel);
+    }
+}
+
+/// Sends a presence event line to every client subscribed with `/subscribe presence`.
+/// Events about invisible users only reach staff subscribers.
+fn publish_presence(server: &Arc<Server>, event: &str, username: &str, channel: Option<&str>, invisible: bool) {
+    // Held until delivery finishes so a concurrent snapshot sees either all of this event or none
+    let Ok(mut presence) = server.presence.lock() else {
+        return;
+    };
+    presence.apply(event, username, channel, invisible);
+
+    let line = presence::format_event(event, username, channel);
+
+    let subscribers: Vec<Recipient> = match server.clients.lock() {
+        Ok(clients_guard) => clients_guard.values()
+            .filter(|client| client.presence_subscribed)
+            .filter_map(Client::recipient)
+            .collect(),
+        Err(_) => return,
+    };
+
+    for subscriber in subscribers {
+        if invisible && !is_staff(server, &subscriber.name) {
+            continue;
+        }
+        let result = subscriber.send(line.as_bytes());
+        record_write_result(&server.clients, &subscriber, result);
+    }
+}
+
+fn is_client_invisible(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client_id: Uuid) -> bool {
+    clients.lock()
+        .map(|clients_guard| clients_guard.get(&client_id).is_some_and(|client| client.invisible))
+        .unwrap_or(false)
+}
+
+/// The staff channel is hidden from everyone else: it can't be listed, found,
+/// inspected, joined or spectated by regular users.
+fn can_see_channel(server: &Arc<Server>, username: &str, channel: &str) -> bool {
+    channel != STAFF_CHANNEL || is_staff(server, username)
+}
+
+/// Posts a moderation notice to the staff channel.
+fn notify_staff(server: &Arc<Server>, notice: &str) {
+    broadcast_to_channel(&server.clients, &server.channel_manager, STAFF_CHANNEL,
+                         &format!("*** [staff] {} ***\n", notice), None);
+}
+
+fn is_staff(server: &Arc<Server>, username: &str) -> bool {
+    server.auth_manager.lock()
+        .map(|auth| auth.get_role(username).is_staff())
+        .unwrap_or(false)
+}
+
+fn is_admin(server: &Arc<Server>, username: &str) -> bool {
+    server.auth_manager.lock()
+        .map(|auth| auth.get_role(username) == Role::Admin)
+        .unwrap_or(false)
+}
+
+/// Returns the channels a freshly authenticated user should be placed in,
+/// together with the one that becomes their active channel.
+fn initial_channels(server: &Arc<Server>, username: &str) -> (Vec<String>, String) {
+    let default_channel = &server.config.default_channel;
+    let prefs = match server.auth_manager.lock() {
+        Ok(auth) => auth.get_prefs(username),
+        Err(_) => return (vec![default_channel.clone()], default_channel.clone()),
+    };
+
+    if prefs.autojoin && let Ok(channel_manager) = server.channel_manager.read() {
+        let channels: Vec<String> = prefs.saved_channels.into_iter()
+            .filter(|name| channel_manager.get_channel(name).is_some_and(|channel| !channel.locked && !channel.hidden)
+                && can_see_channel(server, username, name))
+            .collect();
+
+        let active = prefs.saved_active_channel
+            .filter(|name| channels.contains(name))
+            .or_else(|| channels.first().cloned());
+
+        if let Some(active) = active {
+            return (channels, active);
+        }
+    }
+
+    (vec![default_channel.clone()], default_channel.clone())
+}
+
+fn save_channel_membership(server: &Arc<Server>, username: &str, current_channel: Option<&str>) {
+    let channels = match server.channel_manager.read() {
+        Ok(channel_manager) => channel_manager.channels_of(username),
+        Err(_) => return,
+    };
+
+    if let Ok(mut auth) = server.auth_manager.lock() {
+        let mut prefs = auth.get_prefs(username);
+        if !prefs.autojoin {
+            return;
+        }
+
+        prefs.saved_channels = channels;
+        prefs.saved_active_channel = current_channel.map(str::to_string);
+        if let Err(e) = auth.set_prefs(username, prefs) {
+            eprintln!("Failed to save channel membership for {}: {}", username, e);
+        }
+    }
+}
+
+fn handle_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let parts: Vec<&str> = command.split_whitespace().collect();
+    if parts.is_empty() {
+        return Ok(());
+    }
+
+    match parts[0] {
+        "/whereami" => {
+            handle_whereami_command(stream, server, username, client_id)?;
+        }
+        "/motd" => {
+            match load_banner() {
+                Some(banner) => stream.write_all(&banner)?,
+                None => stream.write_all(b"No message of the day set\n")?,
+            }
+        }
+        "/last" => {
+            handle_last_command(stream, server, &parts, client_id)?;
+        }
+        "/help" => {
+            send_paged(stream, server, client_id, &with_command_prefix(HELP_MESSAGE, server.config.command_prefix()))?;
+        }
+        "/channels" => {
+            let preview = parts.get(1) == Some(&"--preview");
+            let listing = format_channel_list(server, username, preview)?;
+            send_paged(stream, server, client_id, &listing)?;
+        }
+        "/more" => {
+            send_next_page(stream, server, client_id)?;
+        }
+        "/join" => {
+            handle_join_command(stream, server, &parts, username, client_id)?;
+        }
+        "/voice" => {
+            handle_voice_command(stream, server, &parts, username, client_id)?;
+        }
+        "/voicedefaults" => {
+            handle_voicedefaults_command(stream, server, &parts, username)?;
+        }
+        "/voicelimit" => {
+            handle_voicelimit_command(stream, server, &parts, username)?;
+        }
+        "/leave" => {
+            handle_leave_command(stream, server, username, client_id)?;
+        }
+        "/verify" => {
+            handle_verify_command(stream, server, &parts, username)?;
+        }
+        "/create" => {
+            handle_create_command(stream, server, &parts, username)?;
+        }
+        "/search" => {
+            handle_search_command(stream, server, command, username, client_id)?;
+        }
+        "/suggest" => {
+            handle_suggest_command(stream, server, command, username)?;
+        }
+        "/channelinfo" => {
+            handle_channelinfo_command(stream, server, &parts, username, client_id)?;
+        }
+        "/count" => {
+            handle_count_command(stream, server, &parts, username, client_id)?;
+        }
+        "/users" => {
+            handle_users_command(stream, server, username, client_id)?;
+        }
+        "/invisible" => {
+            handle_invisible_command(stream, server, &parts, client_id)?;
+        }
+        "/markdown" => {
+            handle_markdown_command(stream, server, &parts, username, client_id)?;
+        }
+        "/colorscheme" => {
+            handle_colorscheme_command(stream, server, &parts, username, client_id)?;
+        }
+        "/emoji" => {
+            handle_emoji_command(stream, server, &parts, username, client_id)?;
+        }
+        "/setname" => {
+            handle_setname_command(stream, server, command, username)?;
+        }
+        "/receipts" => {
+            handle_receipts_command(stream, server, &parts, username)?;
+        }
+        "/roll" => {
+            handle_roll_command(stream, server, &parts, username, client_id)?;
+        }
+        "/mentionsonly" => {
+            handle_mentionsonly_command(stream, server, &parts, client_id)?;
+        }
+        "/subscribe" | "/unsubscribe" => {
+            handle_subscribe_command(stream, server, &parts, client_id)?;
+        }
+        "/mail" => {
+            handle_mail_command(stream, server, command, username, client_id)?;
+        }
+        "/mailclear" => {
+            let cleared = server.mailbox_manager.lock()
+                .map_err(|_| "Failed to acquire mailbox lock")?
+                .clear(username)?;
+            stream.write_all(format!("Deleted {} messages\n", cleared).as_bytes())?;
+        }
+        "/msg" => {
+            handle_msg_command(stream, server, command, username)?;
+        }
+        "/r" => {
+            handle_reply_command(stream, server, command, username, client_id)?;
+        }
+        "/mods" | "/modlist" => {
+            handle_mods_command(stream, server, username, client_id)?;
+        }
+        "/serverinfo" | "/uptime" => {
+            handle_serverinfo_command(stream, server)?;
+        }
+        "/shutdown" | "/restart" => {
+            handle_shutdown_command(stream, server, &parts, username)?;
+        }
+        "/export" => {
+            handle_export_command(stream, server, username)?;
+        }
+        "/deleteaccount" => {
+            set_pending_action(&server.clients, client_id, PendingAction::DeleteAccount);
+            stream.write_all(format!("This permanently deletes your account. Enter your password to confirm (or {}cancel): ", server.config.command_prefix()).as_bytes())?;
+        }
+        "/autojoin" => {
+            handle_autojoin_command(stream, server, &parts, username)?;
+        }
+        "/autoafk" => {
+            handle_autoafk_command(stream, server, &parts, username)?;
+        }
+        "/announcemode" => {
+            handle_announcemode_command(stream, server, &parts, username, client_id)?;
+        }
+        "/logging" => {
+            handle_logging_command(stream, server, &parts, username, client_id)?;
+        }
+        "/welcome" => {
+            handle_welcome_command(stream, server, command, username, client_id)?;
+        }
+        "/clearhistory" => {
+            handle_clearhistory_command(stream, server, &parts, username, client_id)?;
+        }
+        "/purge" => {
+            handle_purge_command(stream, server, &parts, username, client_id)?;
+        }
+        "/delete" => {
+            handle_delete_command(stream, server, &parts, username)?;
+        }
+        "/hide" => {
+            handle_hide_command(stream, server, &parts, username)?;
+        }
+        "/lock" | "/unlock" => {
+            handle_lock_command(stream, server, &parts, username, client_id)?;
+        }
+        "/evacuate" => {
+            handle_evacuate_command(stream, server, &parts, username)?;
+        }
+        "/summon" => {
+            handle_summon_command(stream, server, &parts, username)?;
+        }
+        "/silence" => {
+            handle_silence_command(stream, server, &parts, username)?;
+        }
+        "/unsilence" => {
+            handle_unsilence_command(stream, server, &parts, username)?;
+        }
+        "/whois" => {
+            handle_whois_command(stream, server, &parts, username)?;
+        }
+        "/connections" => {
+            handle_connections_command(stream, server, username, client_id)?;
+        }
+        "/disconnect" => {
+            handle_disconnect_command(stream, server, &parts, username, client_id)?;
+        }
+        "/listsilences" => {
+            handle_listsilences_command(stream, server, username, client_id)?;
+        }
+        "/say" => {
+            handle_say_command(stream, server, command, username)?;
+        }
+        "/spectate" => {
+            handle_spectate_command(stream, server, &parts, username, client_id)?;
+        }
+        "/unspectate" => {
+            handle_unspectate_command(stream, server, client_id)?;
+        }
+        "/poll" => {
+            handle_poll_command(stream, server, command, username, client_id)?;
+        }
+        "/vote" => {
+            handle_vote_command(stream, server, &parts, username, client_id)?;
+        }
+        "/pollresults" => {
+            handle_pollresults_command(stream, server, &parts, client_id)?;
+        }
+        "/pollclose" => {
+            handle_pollclose_command(stream, server, &parts, username, client_id)?;
+        }
+        _ => {
+            write_hint(stream, server, "Unknown command. Type /help for available commands.\n")?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_join_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if parts.len() < 2 {
+        write_hint(stream, server, "Usage: /join <channel_name>\n")?;
+        return Ok(());
+    }
+
+    let channel_name = parts[1];
+
+    // Get old channel
+    let old_channel = get_client_current_channel(&server.clients, client_id);
+
+    if !can_see_channel(server, username, channel_name) {
+        stream.write_all(b"Channel does not exist\n")?;
+        return Ok(());
+    }
+
+    {
+        let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+
+        match channel_manager.get_channel(channel_name) {
+            None => {
+                stream.write_all(b"Channel does not exist\n")?;
+                return Ok(());
+            }
+            Some(channel) if channel.hidden => {
+                stream.write_all(b"Channel does not exist\n")?;
+                return Ok(());
+            }
+            Some(channel) if channel.locked => {
+                stream.write_all(b"Channel is locked\n")?;
+                return Ok(());
+            }
+            Some(_) => {}
+        }
+    }
+
+    let switch = server.clients.lock()
+        .map_err(|_| "Failed to acquire clients lock")?
+        .get_mut(&client_id)
+        .map(|client| client.channel_switches.record(old_channel.as_deref(), channel_name));
+    let Some((announce_leave, announce_join)) = switch.unwrap_or(Some((true, true))) else {
+        stream.write_all(b"You're switching channels too fast\n")?;
+        return Ok(());
+    };
+
+    {
+        let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+
+        // Leave old channel, join new channel
+        if let Some(old) = &old_channel {
+            channel_manager.leave_channel(old, username);
+        }
+        channel_manager.join_channel(channel_name, username.to_string());
+    } // Release the lock before broadcasting, which locks the channel manager itself
+
+    let invisible = is_client_invisible(&server.clients, client_id);
+
+    if let Some(old) = &old_channel {
+        if !invisible && announce_leave {
+            broadcast_to_channel(&server.clients, &server.channel_manager,
+                                 old,
+                                 &format!("*** {} left the channel ***\n", username),
+                                 None);
+        }
+        publish_presence(server, "leave", username, Some(old), invisible);
+        prune_hidden_channel(server, old);
+    }
+
+    // Update client's current channel
+    if let Ok(mut clients) = server.clients.lock()
+        && let Some(client) = clients.get_mut(&client_id) {
+        client.current_channel = Some(channel_name.to_string());
+    }
+
+    stream.write_all(format!("Joined channel: {}\n", channel_name).as_bytes())?;
+    send_channel_welcome(stream, server, channel_name)?;
+    if !invisible && announce_join {
+        broadcast_to_channel(&server.clients, &server.channel_manager,
+                             channel_name,
+                             &format!("*** {} joined the channel ***\n", username),
+                             Some(client_id));
+    }
+    publish_presence(server, "join", username, Some(channel_name), invisible);
+
+    Ok(())
+}
+
+fn send_channel_welcome(stream: &mut ClientOutput, server: &Arc<Server>, channel_name: &str) -> ServerResult<()> {
+    let welcome = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .get_channel(channel_name)
+        .and_then(|channel| channel.welcome.clone());
+
+    if let Some(welcome) = welcome {
+        stream.write_all(format!("[{}] {}\n", channel_name, welcome).as_bytes())?;
+    }
+    Ok(())
+}
+
+fn handle_voice_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if parts.len() < 2 {
+        write_hint(stream, server, "Usage: /voice <channel_name>\n")?;
+        return Ok(());
+    }
+
+    let channel_name = parts[1];
+    if !can_see_channel(server, username, channel_name) {
+        stream.write_all(b"Voice channel does not exist\n")?;
+        return Ok(());
+    }
+    let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+
+    if let Some(channel) = channel_manager.get_channel(channel_name)
+        && !channel.hidden {
+        if channel.locked {
+            stream.write_all(b"Channel is locked\n")?;
+        } else if channel.channel_type == ChannelType::Voice {
+            let prefs = server.auth_manager.lock()
+                .map_err(|_| "Failed to acquire auth manager lock")?
+                .get_prefs(username);
+            let mut voice_manager = server.voice_manager.lock().map_err(|_| "Failed to acquire voice manager lock")?;
+            match voice_manager.join_voice_channel(client_id, username.to_string(), channel_name.to_string(), channel.max_participants,
+                                                   prefs.default_muted, prefs.default_deafened) {
+                Ok(()) => {
+                    let state = if prefs.default_deafened {
+                        " (muted and deafened)"
+                    } else if prefs.default_muted {
+                        " (muted)"
+                    } else {
+                        ""
+                    };
+                    stream.write_all(format!("Joined voice channel: {}{}\n", channel_name, state).as_bytes())?;
+                    stream.write_all(b"Note: Voice streaming not implemented. This is a placeholder.\n")?;
+                }
+                Err(e) => stream.write_all(format!("{}\n", e).as_bytes())?,
+            }
+        } else {
+            stream.write_all(b"That's not a voice channel\n")?;
+        }
+    } else {
+        stream.write_all(b"Voice channel does not exist\n")?;
+    }
+
+    Ok(())
+}
+
+fn handle_voicedefaults_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let mut auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+    let mut prefs = auth.get_prefs(username);
+
+    let (muted, deafened) = match parts.get(1) {
+        None => {
+            let current = if prefs.default_deafened {
+                "deafened"
+            } else if prefs.default_muted {
+                "muted"
+            } else {
+                "unmuted"
+            };
+            stream.write_all(format!("You join voice channels {}\n", current).as_bytes())?;
+            return Ok(());
+        }
+        Some(&"muted") => (true, false),
+        Some(&"unmuted") => (false, false),
+        Some(&"deafened") => (true, true),
+        Some(_) => {
+            write_hint(stream, server, "Usage: /voicedefaults [muted|unmuted|deafened]\n")?;
+            return Ok(());
+        }
+    };
+
+    prefs.default_muted = muted;
+    prefs.default_deafened = deafened;
+    auth.set_prefs(username, prefs)?;
+    stream.write_all(format!("You will join voice channels {}\n", parts[1]).as_bytes())?;
+    Ok(())
+}
+
+fn handle_voicelimit_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let (Some(channel_name), Some(limit)) = (parts.get(1), parts.get(2).and_then(|n| n.parse::<usize>().ok())) else {
+        write_hint(stream, server, "Usage: /voicelimit <channel> <participants>\n")?;
+        return Ok(());
+    };
+    if !(1..=channel::MAX_PARTICIPANTS_LIMIT).contains(&limit) {
+        stream.write_all(format!("Participants must be between 1 and {}\n", channel::MAX_PARTICIPANTS_LIMIT).as_bytes())?;
+        return Ok(());
+    }
+
+    let result = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .set_max_participants(channel_name, limit);
+    match result {
+        Ok(()) => stream.write_all(format!("{} now allows {} voice participants\n", channel_name, limit).as_bytes())?,
+        Err(e) => stream.write_all(format!("{}\n", e).as_bytes())?,
+    }
+    Ok(())
+}
+
+fn handle_leave_command(stream: &mut ClientOutput, server: &Arc<Server>, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let left = {
+        let mut voice_manager = server.voice_manager.lock().map_err(|_| "Failed to acquire voice manager lock")?;
+        let channel = voice_manager.get_user_session(username).map(|session| session.channel.clone());
+        channel.filter(|_| voice_manager.leave_voice_channel(username, client_id))
+    };
+
+    match left {
+        Some(channel) => {
+            stream.write_all(b"Left voice channel\n")?;
+            prune_hidden_channel(server, &channel);
+        }
+        None => stream.write_all(b"You're not in a voice channel\n")?,
+    }
+    Ok(())
+}
+
+/// `/verify <code>` activates an account registered with email
+/// verification; `/verify resend` mails a fresh code.
+fn handle_verify_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let Some(&argument) = parts.get(1) else {
+        write_hint(stream, server, "Usage: /verify <code>|resend\n")?;
+        return Ok(());
+    };
+
+    if argument != "resend" {
+        let result = server.auth_manager.lock()
+            .map_err(|_| "Failed to acquire auth manager lock")?
+            .verify_email(username, argument);
+        match result {
+            Ok(()) => stream.write_all(b"Email verified. Thanks!\n")?,
+            Err(e) => write_hint(stream, server, &format!("{}\n", e))?,
+        }
+        return Ok(());
+    }
+
+    let address = {
+        let auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+        if auth.is_verified(username) {
+            stream.write_all(b"Your account is already verified\n")?;
+            return Ok(());
+        }
+        if let Some(wait) = auth.resend_wait(username) {
+            stream.write_all(format!("A code was sent recently; you can ask for another in {} seconds\n", wait.as_millis().div_ceil(1000)).as_bytes())?;
+            return Ok(());
+        }
+        auth.plain_email(username)
+    };
+    let Some(address) = address else {
+        stream.write_all(b"A new code can't be sent because only a hash of your email is stored; ask an admin\n")?;
+        return Ok(());
+    };
+    if !server.mailer.is_enabled() {
+        stream.write_all(b"Email is not configured on this server\n")?;
+        return Ok(());
+    }
+
+    let code = email::verification_code();
+    server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .set_verification_code(username, code.clone())?;
+    match server.mailer.send_verification_code(&address, &server.config.server_name, username, &code, server.config.command_prefix()) {
+        Ok(()) => stream.write_all(format!("A new verification code was sent to {}\n", address).as_bytes())?,
+        Err(e) => {
+            eprintln!("Verification email to {} for {} failed: {}", address, username, e);
+            stream.write_all(b"The verification email couldn't be sent; try again later\n")?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_create_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let verified = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .is_verified(username);
+    if !verified {
+        write_hint(stream, server, "Verify your email with /verify <code> before creating channels\n")?;
+        return Ok(());
+    }
+
+    if parts.len() < 3 {
+        write_hint(stream, server, "Usage: /create <name> text|voice\n")?;
+        return Ok(());
+    }
+
+    let channel_name = parts[1];
+    let channel_type = match parts[2] {
+        "text" => ChannelType::Text,
+        "voice" => ChannelType::Voice,
+        _ => {
+            stream.write_all(b"Channel type must be 'text' or 'voice'\n")?;
+            return Ok(());
+        }
+    };
+
+    let mut channel_manager = server.channel_manager.write().map_err(|_| "Failed to acquire channel manager lock")?;
+    match channel_manager.create_channel(channel_name, channel_type) {
+        Ok(()) => stream.write_all(format!("Created {} channel: {}\n", parts[2], channel_name).as_bytes())?,
+        Err(e) => stream.write_all(format!("Could not create channel: {}\n", e).as_bytes())?,
+    }
+
+    Ok(())
+}
+
+fn handle_channelinfo_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    let channel_name = match parts.get(1) {
+        Some(name) => name.to_string(),
+        None => match get_client_current_channel(&server.clients, client_id) {
+            Some(name) => name,
+            None => {
+                write_hint(stream, server, "Usage: /channelinfo [channel]\n")?;
+                return Ok(());
+            }
+        },
+    };
+
+    if !can_see_channel(server, username, &channel_name) {
+        stream.write_all(format!("Channel {} does not exist\n", channel_name).as_bytes())?;
+        return Ok(());
+    }
+
+    let logger_enabled = server.message_logger.lock()
+        .map(|logger| logger.is_enabled())
+        .unwrap_or(false);
+
+    let info = {
+        let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+        channel_manager.get_channel(&channel_name).map(|channel| {
+            let slow_mode = channel.slow_mode
+                .map(|delay| format!("{} seconds", delay.as_secs()))
+                .unwrap_or_else(|| "off".to_string());
+            format!(
+                "=== Channel {} ===\n\
+                 Type: {:?}\n\
+                 Users: {}\n\
+                 Locked: {}\n\
+                 Announcement mode: {}\n\
+                 Slow mode: {}\n\
+                 Messages in the last {}s: {}\n\
+                 Logging: {}\n\
+                 Welcome message: {}\n",
+                channel.name,
+                channel.channel_type,
+                channel.users.len(),
+                if channel.locked { "yes" } else { "no" },
+                if channel.announcement { "on" } else { "off" },
+                slow_mode,
+                channel::RATE_WINDOW.as_secs(),
+                channel.message_rate(),
+                if logger_enabled && channel.logging { "on" } else { "off" },
+                channel.welcome.as_deref().unwrap_or("(none)"),
+            )
+        })
+    };
+
+    match info {
+        Some(info) => stream.write_all(info.as_bytes())?,
+        None => stream.write_all(format!("Channel {} does not exist\n", channel_name).as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Top talkers in a channel since startup. Moderators get everyone, paged;
+/// other users get the top `COUNT_SUMMARY_SIZE` and a total for the rest.
+fn handle_count_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    let channel_name = match parts.get(1) {
+        Some(name) => name.to_string(),
+        None => match get_client_current_channel(&server.clients, client_id) {
+            Some(name) => name,
+            None => {
+                write_hint(stream, server, "Usage: /count [channel]\n")?;
+                return Ok(());
+            }
+        },
+    };
+
+    if !can_see_channel(server, username, &channel_name) {
+        stream.write_all(format!("Channel {} does not exist\n", channel_name).as_bytes())?;
+        return Ok(());
+    }
+
+    let counts = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .get_channel(&channel_name)
+        .map(|channel| channel.message_counts());
+    let Some(mut counts) = counts else {
+        stream.write_all(format!("Channel {} does not exist\n", channel_name).as_bytes())?;
+        return Ok(());
+    };
+    if counts.is_empty() {
+        stream.write_all(format!("No messages in {} since the server started\n", channel_name).as_bytes())?;
+        return Ok(());
+    }
+
+    if is_staff(server, username) {
+        let mut response = format!("=== Messages in {} since startup ===\n", channel_name);
+        for (name, count) in &counts {
+            response.push_str(&format!("{}: {}\n", name, count));
+        }
+        return send_paged(stream, server, client_id, &response);
+    }
+
+    let rest: u64 = counts.iter().skip(COUNT_SUMMARY_SIZE).map(|(_, count)| count).sum();
+    let others = counts.len().saturating_sub(COUNT_SUMMARY_SIZE);
+    counts.truncate(COUNT_SUMMARY_SIZE);
+    let mut summary = counts.iter()
+        .map(|(name, count)| format!("{}: {}", name, count))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if others > 0 {
+        summary.push_str(&format!(" (+{} others: {})", others, rest));
+    }
+    stream.write_all(format!("Messages in {} since startup: {}\n", channel_name, summary).as_bytes())?;
+    Ok(())
+}
+
+fn handle_users_command(stream: &mut ClientOutput, server: &Arc<Server>, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let current_channel = get_client_current_channel(&server.clients, client_id);
+
+    if let Some(channel) = current_channel {
+        let invisible_users = invisible_usernames(&server.clients);
+        let display_names = display_names(&server.clients);
+        let show_invisible = is_staff(server, username);
+
+        let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+        if let Some(ch) = channel_manager.get_channel(&channel) {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            let users: Vec<String> = ch.users.iter()
+                .filter_map(|member| {
+                    let user = &member.name;
+                    let name = match display_names.get(user) {
+                        Some(display) => format!("{} [{}]", display, user),
+                        None => user.clone(),
+                    };
+                    let label = if !invisible_users.contains(user) {
+                        name
+                    } else if show_invisible || user == username {
+                        format!("{} (invisible)", name)
+                    } else {
+                        return None;
+                    };
+                    if member.joined_at == 0 {
+                        Some(label)
+                    } else {
+                        Some(format!("{} (joined {} ago)", label, format_elapsed(now.saturating_sub(member.joined_at))))
+                    }
+                })
+                .collect();
+            stream.write_all(format!("Users in {}: {}\n", channel, users.join(", ")).as_bytes())?;
+        }
+    } else {
+        stream.write_all(b"You're not in any channel\n")?;
+    }
+
+    Ok(())
+}
+
+/// Compact age such as `45s`, `5m`, `3h` or `2d`.
+fn format_elapsed(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn display_name(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client_id: Uuid) -> Option<String> {
+    clients.lock().ok()?
+        .get(&client_id)?
+        .display_name.clone()
+}
+
+/// Login name to display name for every connected user who has set one.
+fn display_names(clients: &Arc<Mutex<HashMap<Uuid, Client>>>) -> HashMap<String, String> {
+    clients.lock()
+        .map(|clients_guard| clients_guard.values()
+            .filter_map(|client| client.display_name.clone().map(|display| (client.user.name.clone(), display)))
+            .collect())
+        .unwrap_or_default()
+}
+
+fn invisible_usernames(clients: &Arc<Mutex<HashMap<Uuid, Client>>>) -> Vec<String> {
+    clients.lock()
+        .map(|clients_guard| clients_guard.values()
+            .filter(|client| client.invisible)
+            .map(|client| client.user.name.clone())
+            .collect())
+        .unwrap_or_default()
+}
+
+fn handle_invisible_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], client_id: Uuid) -> ServerResult<()> {
+    let invisible = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /invisible on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    if let Ok(mut clients) = server.clients.lock()
+        && let Some(client) = clients.get_mut(&client_id) {
+        client.invisible = invisible;
+    }
+
+    if invisible {
+        stream.write_all(b"You are now invisible. You still receive messages but are hidden from user lists and join/leave notices.\n")?;
+    } else {
+        stream.write_all(b"You are now visible\n")?;
+    }
+    Ok(())
+}
+
+fn handle_colorscheme_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    let Some(name) = parts.get(1) else {
+        let current = server.auth_manager.lock()
+            .map_err(|_| "Failed to acquire auth manager lock")?
+            .get_prefs(username)
+            .color_scheme;
+        let schemes: Vec<String> = format::COLOR_SCHEMES.iter()
+            .map(|scheme| if *scheme == current {
+                format!("{} (current)", scheme.name())
+            } else {
+                scheme.name().to_string()
+            })
+            .collect();
+        write_hint(stream, server, &format!("Color schemes: {}\nUsage: /colorscheme <name>\n", schemes.join(", ")))?;
+        return Ok(());
+    };
+
+    let Some(scheme) = format::ColorScheme::from_name(name) else {
+        let names: Vec<&str> = format::COLOR_SCHEMES.iter().map(|scheme| scheme.name()).collect();
+        stream.write_all(format!("Unknown color scheme {}; available: {}\n", name, names.join(", ")).as_bytes())?;
+        return Ok(());
+    };
+
+    {
+        let mut auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+        let mut prefs = auth.get_prefs(username);
+        prefs.color_scheme = scheme;
+        auth.set_prefs(username, prefs)?;
+    }
+
+    if let Ok(mut clients) = server.clients.lock()
+        && let Some(client) = clients.get_mut(&client_id) {
+        client.color_scheme = scheme;
+    }
+
+    stream.write_all(format!("Color scheme set to {}\n", scheme.name()).as_bytes())?;
+    Ok(())
+}
+
+/// Reports the caller's active channel, any other channels the account is
+/// a member of (from other sessions), and its voice and spectating state.
+fn handle_whereami_command(stream: &mut ClientOutput, server: &Arc<Server>, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let Some(current) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    let mut response = String::new();
+    let spectating = {
+        let manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+        let describe = |name: &str| match manager.get_channel(name).map(|ch| ch.channel_type.clone()) {
+            Some(channel_type) => format!("{} {} ({:?})", channel_icon(&channel_type), name, channel_type),
+            None => name.to_string(),
+        };
+
+        response.push_str(&format!("Active channel: {}\n", describe(&current)));
+        let others: Vec<String> = manager.channels_of(username).into_iter()
+            .filter(|name| *name != current)
+            .map(|name| describe(&name))
+            .collect();
+        if !others.is_empty() {
+            response.push_str(&format!("Also joined: {}\n", others.join(", ")));
+        }
+        manager.spectated_channel(client_id)
+    };
+
+    let voice = server.voice_manager.lock()
+        .map_err(|_| "Failed to acquire voice manager lock")?
+        .get_user_session(username)
+        .filter(|session| session.connection_id == client_id)
+        .map(|session| session.channel.clone());
+    if let Some(voice) = voice {
+        response.push_str(&format!("Voice: {} {}\n", channel_icon(&ChannelType::Voice), voice));
+    }
+    if let Some(spectating) = spectating {
+        response.push_str(&format!("Spectating: {}\n", spectating));
+    }
+
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+fn handle_last_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], client_id: Uuid) -> ServerResult<()> {
+    let count = match parts.get(1).map(|n| n.parse::<usize>()) {
+        None => DEFAULT_LAST_COMMANDS,
+        Some(Ok(n)) if (1..=client::COMMAND_HISTORY_SIZE).contains(&n) => n,
+        Some(_) => {
+            write_hint(stream, server, &format!("Usage: /last [1-{}]\n", client::COMMAND_HISTORY_SIZE))?;
+            return Ok(());
+        }
+    };
+
+    let history: Vec<String> = server.clients.lock()
+        .map_err(|_| "Failed to acquire clients lock")?
+        .get(&client_id)
+        .map(|client| client.command_history.iter().rev().take(count).rev().cloned().collect())
+        .unwrap_or_default();
+
+    if history.is_empty() {
+        stream.write_all(b"No commands yet\n")?;
+        return Ok(());
+    }
+
+    let mut response = String::from("=== Recent commands ===\n");
+    for (i, command) in history.iter().enumerate() {
+        response.push_str(&format!("{:>2}. {}\n", i + 1, command));
+    }
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+fn handle_markdown_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    let enabled = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /markdown on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    {
+        let mut auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+        let mut prefs = auth.get_prefs(username);
+        prefs.markdown = enabled;
+        auth.set_prefs(username, prefs)?;
+    }
+
+    if let Ok(mut clients) = server.clients.lock()
+        && let Some(client) = clients.get_mut(&client_id) {
+        client.markdown = enabled;
+    }
+
+    if enabled {
+        stream.write_all(b"Markdown rendering enabled: *bold*, _italic_ and `code` are shown with ANSI formatting\n")?;
+    } else {
+        stream.write_all(b"Markdown rendering disabled\n")?;
+    }
+    Ok(())
+}
+
+fn handle_emoji_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    let enabled = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /emoji on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    {
+        let mut auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+        let mut prefs = auth.get_prefs(username);
+        prefs.emoji = enabled;
+        auth.set_prefs(username, prefs)?;
+    }
+
+    if let Ok(mut clients) = server.clients.lock()
+        && let Some(client) = clients.get_mut(&client_id) {
+        client.emoji = enabled;
+    }
+
+    if enabled {
+        stream.write_all(b"Emoji shortcodes like :smile: are now shown as emoji\n")?;
+    } else {
+        stream.write_all(b"Emoji shortcodes are now shown as typed\n")?;
+    }
+    Ok(())
+}
+
+fn handle_setname_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str) -> ServerResult<()> {
+    let display = command.split_once(char::is_whitespace)
+        .map(|(_, rest)| rest.trim())
+        .filter(|rest| !rest.is_empty());
+
+    if let Some(display) = display
+        && let Err(e) = user::validate_display_name(display) {
+        stream.write_all(format!("{}\n", e).as_bytes())?;
+        return Ok(());
+    }
+
+    {
+        let mut auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+        if let Some(display) = display
+            && auth.display_name_taken(display, username) {
+            stream.write_all(b"That name is already in use\n")?;
+            return Ok(());
+        }
+        let mut prefs = auth.get_prefs(username);
+        prefs.display_name = display.map(str::to_string);
+        auth.set_prefs(username, prefs)?;
+    }
+
+    // Every session of the account shows the new name
+    if let Ok(mut clients) = server.clients.lock() {
+        for client in clients.values_mut().filter(|client| client.user.name == username) {
+            client.display_name = display.map(str::to_string);
+        }
+    }
+
+    match display {
+        Some(display) => stream.write_all(format!("Your display name is now {}\n", display).as_bytes())?,
+        None => stream.write_all(b"Display name cleared; your login name is shown again\n")?,
+    }
+    Ok(())
+}
+
+fn handle_receipts_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let enabled = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /receipts on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    {
+        let mut auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+        let mut prefs = auth.get_prefs(username);
+        prefs.dm_receipts = enabled;
+        auth.set_prefs(username, prefs)?;
+    }
+
+    if enabled {
+        stream.write_all(b"Delivery receipts for direct messages enabled\n")?;
+    } else {
+        stream.write_all(b"Delivery receipts for direct messages disabled\n")?;
+    }
+    Ok(())
+}
+
+fn handle_roll_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    let Some(notation) = parts.get(1) else {
+        write_hint(stream, server, "Usage: /roll <NdM>, e.g. /roll 2d6\n")?;
+        return Ok(());
+    };
+
+    let dice = match Dice::parse(notation) {
+        Ok(dice) => dice,
+        Err(e) => {
+            stream.write_all(format!("{}\n", e).as_bytes())?;
+            return Ok(());
+        }
+    };
+
+    let roller = display_name(&server.clients, client_id).unwrap_or_else(|| username.to_string());
+    let Some(post) = admit_post(stream, server, username, client_id, |channel| {
+        let rolls = dice.roll();
+        let total: u32 = rolls.iter().sum();
+        format!("[{}] {} rolls {}d{}: {:?} = {}\n", channel, roller, dice.count, dice.sides, rolls, total)
+    }) else {
+        return Ok(());
+    };
+    broadcast_to_channel(&server.clients, &server.channel_manager, &post.channel, &post.text, None);
+    announce_slow_mode_change(server, &post);
+    Ok(())
+}
+
+fn handle_mentionsonly_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], client_id: Uuid) -> ServerResult<()> {
+    let enabled = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /mentionsonly on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    if let Ok(mut clients) = server.clients.lock()
+        && let Some(client) = clients.get_mut(&client_id) {
+        if enabled {
+            client.mentions_only.insert(channel.clone());
+        } else {
+            client.mentions_only.remove(&channel);
+        }
+    }
+
+    if enabled {
+        stream.write_all(format!("Mentions-only enabled for {}: you'll only see messages that @mention you\n", channel).as_bytes())?;
+    } else {
+        stream.write_all(format!("Mentions-only disabled for {}\n", channel).as_bytes())?;
+    }
+    Ok(())
+}
+
+fn handle_subscribe_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], client_id: Uuid) -> ServerResult<()> {
+    if parts.get(1) != Some(&"presence") {
+        write_hint(stream, server, &format!("Usage: {} presence\n", parts[0]))?;
+        return Ok(());
+    }
+
+    let subscribe = parts[0] == "/subscribe";
+    if !subscribe {
+        if let Ok(mut clients) = server.clients.lock()
+            && let Some(client) = clients.get_mut(&client_id) {
+            client.presence_subscribed = false;
+        }
+        stream.write_all(b"Unsubscribed from presence events\n")?;
+        return Ok(());
+    }
+
+    stream.write_all(b"Subscribed to presence events\n")?;
+
+    // Registering and snapshotting under the presence lock means no event can
+    // land between the two: everything after the snapshot arrives as a delta.
+    let presence = server.presence.lock().map_err(|_| "Failed to acquire presence lock")?;
+    let subscriber = {
+        let mut clients = server.clients.lock().map_err(|_| "Failed to acquire clients lock")?;
+        let client = clients.get_mut(&client_id).ok_or("Client not found")?;
+        client.presence_subscribed = true;
+        client.recipient().ok_or("Client has no writer")?
+    };
+    let snapshot = presence::format_snapshot(&presence.snapshot(is_staff(server, &subscriber.name)));
+    let result = subscriber.send(snapshot.as_bytes());
+    record_write_result(&server.clients, &subscriber, result);
+    Ok(())
+}
+
+fn handle_msg_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str) -> ServerResult<()> {
+    let mut args = command.splitn(3, char::is_whitespace).skip(1);
+    match (args.next(), args.next().map(str::trim)) {
+        (Some(recipient), Some(text)) if !recipient.is_empty() && !text.is_empty() => {
+            send_direct_message(stream, server, username, recipient, text)
+        }
+        _ => {
+            write_hint(stream, server, "Usage: /msg <user> <message>\n")?;
+            Ok(())
+        }
+    }
+}
+
+fn handle_mail_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let mut args = command.splitn(3, char::is_whitespace).skip(1);
+    let (recipient, text) = match (args.next(), args.next().map(str::trim)) {
+        (None, _) => {
+            let mail = server.mailbox_manager.lock()
+                .map_err(|_| "Failed to acquire mailbox lock")?
+                .messages(username);
+            if mail.is_empty() {
+                stream.write_all(b"You have no messages\n")?;
+            } else {
+                send_paged(stream, server, client_id, &format_mail(&mail, server.config.command_prefix()))?;
+            }
+            return Ok(());
+        }
+        (Some(recipient), Some(text)) if !text.is_empty() => (recipient, text),
+        _ => {
+            write_hint(stream, server, "Usage: /mail <user> <message> | /mail\n")?;
+            return Ok(());
+        }
+    };
+
+    if contains_control_chars(text) {
+        stream.write_all(b"Message contains invalid characters\n")?;
+        return Ok(());
+    }
+
+    let exists = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .account_info(recipient)
+        .is_some();
+    if !exists {
+        stream.write_all(format!("User {} does not exist\n", recipient).as_bytes())?;
+        return Ok(());
+    }
+
+    let online = server.clients.lock()
+        .map_err(|_| "Failed to acquire clients lock")?
+        .values()
+        .any(|client| client.user.name == recipient);
+    if online {
+        write_hint(stream, server, &format!("{} is online; use /msg instead\n", recipient))?;
+        return Ok(());
+    }
+
+    let result = server.mailbox_manager.lock()
+        .map_err(|_| "Failed to acquire mailbox lock")?
+        .send(recipient, username, text);
+    match result {
+        Ok(()) => stream.write_all(format!("Message stored for {}\n", recipient).as_bytes())?,
+        Err(e) => stream.write_all(format!("Message not stored: {}\n", e).as_bytes())?,
+    }
+    Ok(())
+}
+
+fn format_mail(mail: &[Mail], prefix: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let mut response = format!("You have {} messages:\n", mail.len());
+    for message in mail {
+        response.push_str(&format!("  [{} ago] {}: {}\n",
+            format_elapsed(now.saturating_sub(message.sent_at)), message.from, message.text));
+    }
+    response.push_str(&format!("Use {}mailclear to delete them\n", prefix));
+    response
+}
+
+fn handle_reply_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let text = command.strip_prefix("/r").unwrap_or_default().trim();
+    if text.is_empty() {
+        write_hint(stream, server, "Usage: /r <message>\n")?;
+        return Ok(());
+    }
+
+    let last_dm_from = server.clients.lock()
+        .map_err(|_| "Failed to acquire clients lock")?
+        .get(&client_id)
+        .and_then(|client| client.last_dm_from.clone());
+
+    match last_dm_from {
+        Some(recipient) => send_direct_message(stream, server, username, &recipient, text),
+        None => {
+            stream.write_all(b"No one to reply to\n")?;
+            Ok(())
+        }
+    }
+}
+
+/// Delivers a direct message to every connection of `recipient` and remembers
+/// the sender on each of them so `/r` can answer.
+fn send_direct_message(stream: &mut ClientOutput, server: &Arc<Server>, sender: &str, recipient: &str, text: &str) -> ServerResult<()> {
+    if contains_control_chars(text) {
+        stream.write_all(b"Message contains invalid characters\n")?;
+        return Ok(());
+    }
+
+    let recipients: Vec<Recipient> = {
+        let mut clients = server.clients.lock().map_err(|_| "Failed to acquire clients lock")?;
+        clients.values_mut()
+            .filter(|client| client.user.name == recipient)
+            .filter_map(|client| {
+                client.last_dm_from = Some(sender.to_string());
+                client.recipient()
+            })
+            .collect()
+    };
+
+    let receipts = server.auth_manager.lock()
+        .map(|auth| auth.get_prefs(sender).dm_receipts)
+        .unwrap_or(false);
+
+    if recipients.is_empty() {
+        if receipts {
+            stream.write_all(format!("✗ not delivered to {} (offline)\n", recipient).as_bytes())?;
+        } else {
+            stream.write_all(format!("User {} is not online\n", recipient).as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    let message = format!("[DM from {}] {}\n", sender, text);
+    let mut delivered = false;
+    for client in recipients {
+        let result = client.send(message.as_bytes());
+        delivered |= result.is_ok();
+        record_write_result(&server.clients, &client, result);
+    }
+
+    stream.write_all(format!("[DM to {}] {}\n", recipient, text).as_bytes())?;
+    if receipts {
+        if delivered {
+            stream.write_all(format!("✓ delivered to {}\n", recipient).as_bytes())?;
+        } else {
+            stream.write_all(format!("✗ not delivered to {} (connection lost)\n", recipient).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_mods_command(stream: &mut ClientOutput, server: &Arc<Server>, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let staff = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .staff();
+
+    if staff.is_empty() {
+        stream.write_all(b"There are no moderators on this server\n")?;
+        return Ok(());
+    }
+
+    // Invisible staff appear offline to everyone but other staff
+    let show_invisible = is_staff(server, username);
+    let online: Vec<String> = server.clients.lock()
+        .map_err(|_| "Failed to acquire clients lock")?
+        .values()
+        .filter(|client| show_invisible || !client.invisible)
+        .map(|client| client.user.name.clone())
+        .collect();
+
+    let mut response = String::from("\n=== Staff ===\n");
+    for (name, role) in staff {
+        let status = if online.contains(&name) { "online" } else { "offline" };
+        response.push_str(&format!("{} ({}) - {}\n", name, role, status));
+    }
+    response.push_str("=============\n");
+
+    send_paged(stream, server, client_id, &response)
+}
+
+/// Aggregate, non-sensitive server details for any user.
+fn handle_serverinfo_command(stream: &mut ClientOutput, server: &Arc<Server>) -> ServerResult<()> {
+    let (text_channels, voice_channels) = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .list_channels()
+        .iter()
+        .fold((0, 0), |(text, voice), (_, channel_type, _, _)| match channel_type {
+            ChannelType::Text => (text + 1, voice),
+            ChannelType::Voice => (text, voice + 1),
+        });
+
+    let (delivered, throttled) = server.broadcast_limiter.lock()
+        .map_err(|_| "Failed to acquire broadcast limiter lock")?
+        .totals();
+
+    // Invisible users are left out so the count doesn't reveal them
+    let online = server.clients.lock()
+        .map_err(|_| "Failed to acquire clients lock")?
+        .values()
+        .filter(|client| !client.invisible)
+        .count();
+
+    let response = format!(
+        "\n=== {} ===\n\
+         Version: {}\n\
+         Uptime: {}\n\
+         Channels: {} text, {} voice\n\
+         Online users: {}\n\
+         Message logging: {}\n\
+         Deliveries: {} ({} lines throttled)\n\
+         TLS: not supported\n\
+         ==============\n",
+        server.config.server_name,
+        env!("CARGO_PKG_VERSION"),
+        format_elapsed(server.started_at.elapsed().as_secs()),
+        text_channels, voice_channels,
+        online,
+        if server.config.log_messages { "on" } else { "off" },
+        delivered, throttled);
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+fn handle_shutdown_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let is_admin = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .get_role(username) == Role::Admin;
+    if !is_admin {
+        stream.write_all(b"Only admins can use this command\n")?;
+        return Ok(());
+    }
+
+    let delay = match parts.get(1).map(|s| s.parse::<u64>()) {
+        None => DEFAULT_SHUTDOWN_DELAY_SECS,
+        Some(Ok(secs)) if secs <= MAX_SHUTDOWN_DELAY_SECS => secs,
+        Some(_) => {
+            write_hint(stream, server, &format!("Usage: {} [seconds] (0-{})\n", parts[0], MAX_SHUTDOWN_DELAY_SECS))?;
+            return Ok(());
+        }
+    };
+
+    {
+        let mut scheduled = server.shutdown_scheduled.lock().map_err(|_| "Failed to acquire shutdown lock")?;
+        if *scheduled {
+            stream.write_all(b"A shutdown is already in progress\n")?;
+            return Ok(());
+        }
+        *scheduled = true;
+    }
+
+    let (verb, exit_code) = if parts[0] == "/restart" {
+        ("restarting", RESTART_EXIT_CODE)
+    } else {
+        ("shutting down", 0)
+    };
+    println!("{} scheduled a server {} in {} seconds", username, &parts[0][1..], delay);
+
+    let server = Arc::clone(server);
+    thread::spawn(move || {
+        for (remaining, wait) in countdown_steps(delay) {
+            broadcast_to_all(&server, &format!("*** Server {} in {} seconds ***\n", verb, remaining));
+            thread::sleep(Duration::from_secs(wait));
+        }
+        let _ = server.shutdown_tx.send(exit_code);
+    });
+    Ok(())
+}
+
+/// Plans a shutdown countdown as (seconds left to announce, seconds to wait
+/// afterwards), announcing at the start and once at each checkpoint still ahead
+fn countdown_steps(delay: u64) -> Vec<(u64, u64)> {
+    let mut steps = Vec::new();
+    let mut remaining = delay;
+    for checkpoint in [60, 30, 10, 5, 0] {
+        if checkpoint >= remaining {
+            continue;
+        }
+        steps.push((remaining, remaining - checkpoint));
+        remaining = checkpoint;
+    }
+    steps
+}
+
+/// Sends a notice to every connected client.
+fn broadcast_to_all(server: &Arc<Server>, message: &str) {
+    let recipients: Vec<Recipient> = match server.clients.lock() {
+        Ok(clients) => clients.values().filter_map(Client::recipient).collect(),
+        Err(_) => return,
+    };
+    for client in recipients {
+        let result = client.send(message.as_bytes());
+        record_write_result(&server.clients, &client, result);
+    }
+}
+
+/// Tells everyone the server is going away, saves each connected user's
+/// channel membership for auto-rejoin, closes every connection and exits
+/// the process with `exit_code`.
+fn shut_down(server: &Arc<Server>, exit_code: i32) -> ! {
+    let (verb, reason) = if exit_code == RESTART_EXIT_CODE {
+        ("restarting", DisconnectReason::Restart)
+    } else {
+        ("shutting down", DisconnectReason::Shutdown)
+    };
+    broadcast_to_all(server, &format!("*** Server {} now ***\n{}", verb, reason.line()));
+
+    let sessions: Vec<(String, Option<String>)> = server.clients.lock()
+        .map(|clients| clients.values()
+            .map(|client| (client.user.name.clone(), client.current_channel.clone()))
+            .collect())
+        .unwrap_or_default();
+    for (username, current_channel) in &sessions {
+        save_channel_membership(server, username, current_channel.as_deref());
+    }
+
+    // Give the writer threads a moment to deliver the notice before the sockets close
+    thread::sleep(SHUTDOWN_FLUSH_DELAY);
+    if let Ok(clients) = server.clients.lock() {
+        for client in clients.values() {
+            let _ = client.stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    println!("Server stopped (exit code {})", exit_code);
+    std::process::exit(exit_code);
+}
+
+fn handle_export_command(stream: &mut ClientOutput, server: &Arc<Server>, username: &str) -> ServerResult<()> {
+    let account = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .account_info(username)
+        .ok_or("Account not found")?;
+
+    let messages = server.message_logger.lock()
+        .map_err(|_| "Failed to acquire message logger lock")?
+        .messages_by(username, EXPORT_MESSAGE_LIMIT)?;
+
+    let export = serde_json::json!({
+        "account": account,
+        "recent_messages": messages,
+    });
+    let json = serde_json::to_string_pretty(&export)?;
+
+    stream.write_all(b"=== Your data export ===\n")?;
+    stream.write_all(json.as_bytes())?;
+    stream.write_all(b"\n========================\n")?;
+    Ok(())
+}
+
+fn handle_autojoin_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let enabled = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /autojoin on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    let mut auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+    let mut prefs = auth.get_prefs(username);
+    prefs.autojoin = enabled;
+    if !enabled {
+        prefs.saved_channels.clear();
+        prefs.saved_active_channel = None;
+    }
+    auth.set_prefs(username, prefs)?;
+
+    if enabled {
+        stream.write_all(b"Auto-rejoin enabled. Your channels will be restored on next login.\n")?;
+    } else {
+        stream.write_all(b"Auto-rejoin disabled. You will start in the default channel.\n")?;
+    }
+    Ok(())
+}
+
+fn handle_autoafk_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let enabled = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /autoafk on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    let mut auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+    let mut prefs = auth.get_prefs(username);
+    prefs.auto_afk = enabled;
+    auth.set_prefs(username, prefs)?;
+
+    if enabled {
+        stream.write_all(b"Auto-AFK enabled\n")?;
+    } else {
+        stream.write_all(b"Auto-AFK disabled\n")?;
+    }
+    Ok(())
+}
+
+/// Makes the current channel read-only for everyone but staff, or lifts it.
+fn handle_announcemode_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let enabled = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /announcemode on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .set_announcement(&channel, enabled)?;
+
+    let notice = if enabled {
+        format!("*** {} turned on announcement mode: only moderators can post here ***\n", username)
+    } else {
+        format!("*** {} turned off announcement mode: everyone can post again ***\n", username)
+    };
+    broadcast_to_channel(&server.clients, &server.channel_manager, &channel, &notice, None);
+    Ok(())
+}
+
+fn handle_logging_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let enabled = match parts.get(1) {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            write_hint(stream, server, "Usage: /logging on|off\n")?;
+            return Ok(());
+        }
+    };
+
+    let logger_enabled = server.message_logger.lock()
+        .map(|logger| logger.is_enabled())
+        .unwrap_or(false);
+    if !logger_enabled {
+        stream.write_all(b"Message logging is not enabled on this server\n")?;
+        return Ok(());
+    }
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    {
+        let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+        channel_manager.set_logging(&channel, enabled)?;
+    }
+
+    let notice = if enabled {
+        format!("*** {} enabled logging: messages in this channel are now recorded ***\n", username)
+    } else {
+        format!("*** {} disabled logging: messages in this channel are no longer recorded ***\n", username)
+    };
+    broadcast_to_channel(&server.clients, &server.channel_manager, &channel, &notice, None);
+    Ok(())
+}
+
+fn handle_welcome_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let text = command.split_once(char::is_whitespace)
+        .map(|(_, rest)| rest.trim())
+        .unwrap_or("");
+    if text.is_empty() {
+        write_hint(stream, server, "Usage: /welcome <text> | /welcome clear\n")?;
+        return Ok(());
+    }
+    if contains_control_chars(text) {
+        stream.write_all(b"Welcome message contains invalid characters\n")?;
+        return Ok(());
+    }
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    let welcome = (text != "clear").then(|| text.to_string());
+    let cleared = welcome.is_none();
+    {
+        let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+        channel_manager.set_welcome(&channel, welcome)?;
+    }
+
+    if cleared {
+        stream.write_all(format!("Welcome message for {} cleared\n", channel).as_bytes())?;
+    } else {
+        stream.write_all(format!("Welcome message for {} updated\n", channel).as_bytes())?;
+    }
+    Ok(())
+}
+
+fn handle_purge_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let Some(count) = parts.get(1).and_then(|n| n.parse::<usize>().ok()).filter(|&n| n > 0) else {
+        write_hint(stream, server, "Usage: /purge <count>\n")?;
+        return Ok(());
+    };
+    if count > MAX_PURGE_COUNT {
+        stream.write_all(format!("You can purge at most {} messages at once\n", MAX_PURGE_COUNT).as_bytes())?;
+        return Ok(());
+    }
+
+    let logger_enabled = server.message_logger.lock()
+        .map(|logger| logger.is_enabled())
+        .unwrap_or(false);
+    if !logger_enabled {
+        stream.write_all(b"Message logging is not enabled on this server, so there is no history to purge\n")?;
+        return Ok(());
+    }
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    if count > PURGE_CONFIRM_THRESHOLD {
+        set_pending_action(&server.clients, client_id, PendingAction::Purge { channel: channel.clone(), count });
+        stream.write_all(format!("Purge the last {} messages in {}? Type yes to confirm: ", count, channel).as_bytes())?;
+        return Ok(());
+    }
+
+    purge_channel(stream, server, &channel, count, username)
+}
+
+fn handle_clearhistory_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    // Chat lines aren't kept in memory, so the disk log is the only history there is
+    if parts.get(1) != Some(&"--disk") {
+        write_hint(stream, server, "Channel history only exists in the on-disk log; use /clearhistory --disk to delete it\n")?;
+        return Ok(());
+    }
+
+    let logger_enabled = server.message_logger.lock()
+        .map(|logger| logger.is_enabled())
+        .unwrap_or(false);
+    if !logger_enabled {
+        stream.write_all(b"Message logging is not enabled on this server, so there is no history to clear\n")?;
+        return Ok(());
+    }
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    set_pending_action(&server.clients, client_id, PendingAction::ClearHistory { channel: channel.clone() });
+    stream.write_all(format!("Delete the entire message log of {}? Type yes to confirm: ", channel).as_bytes())?;
+    Ok(())
+}
+
+fn clear_channel_history(server: &Arc<Server>, channel: &str, username: &str) -> ServerResult<()> {
+    let cleared = server.message_logger.lock()
+        .map_err(|_| "Failed to acquire message logger lock")?
+        .clear(channel)?;
+    if let Ok(manager) = server.channel_manager.read() {
+        manager.forget_last_message(channel);
+    }
+
+    println!("{} cleared the history of {} ({} messages)", username, channel, cleared);
+    notify_staff(server, &format!("{} cleared the history of {} ({} messages)", username, channel, cleared));
+    broadcast_to_channel(&server.clients, &server.channel_manager, channel,
+                         &format!("*** channel history cleared by {} ***\n", username), None);
+    Ok(())
+}
+
+fn purge_channel(stream: &mut ClientOutput, server: &Arc<Server>, channel: &str, count: usize, username: &str) -> ServerResult<()> {
+    let purged = server.message_logger.lock()
+        .map_err(|_| "Failed to acquire message logger lock")?
+        .purge_recent(channel, count)?;
+    if let Ok(manager) = server.channel_manager.read() {
+        manager.forget_last_message(channel);
+    }
+
+    if purged == 0 {
+        stream.write_all(b"No messages to purge\n")?;
+        return Ok(());
+    }
+
+    println!("{} purged {} messages from {}", username, purged, channel);
+    notify_staff(server, &format!("{} purged {} messages from {}", username, purged, channel));
+    broadcast_to_channel(&server.clients, &server.channel_manager, channel,
+                         &format!("*** {} purged {} messages ***\n", username, purged), None);
+    Ok(())
+}
+
+/// Stops or resumes new joins to a channel (the current one by default)
+/// without affecting its members.
+fn handle_lock_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let locked = parts[0] == "/lock";
+    let channel = match parts.get(1) {
+        Some(name) => name.to_string(),
+        None => match get_client_current_channel(&server.clients, client_id) {
+            Some(name) => name,
+            None => {
+                write_hint(stream, server, &format!("Usage: {} [channel]\n", parts[0]))?;
+                return Ok(());
+            }
+        },
+    };
+    if locked && channel == server.config.default_channel {
+        stream.write_all(b"The default channel cannot be locked\n")?;
+        return Ok(());
+    }
+
+    let result = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .set_locked(&channel, locked);
+    if let Err(e) = result {
+        stream.write_all(format!("{}\n", e).as_bytes())?;
+        return Ok(());
+    }
+
+    let notice = if locked {
+        format!("*** {} locked the channel: no new joins ***\n", username)
+    } else {
+        format!("*** {} unlocked the channel ***\n", username)
+    };
+    broadcast_to_channel(&server.clients, &server.channel_manager, &channel, &notice, None);
+    stream.write_all(format!("{} {}\n", channel, if locked { "locked" } else { "unlocked" }).as_bytes())?;
+    Ok(())
+}
+
+/// Deletes a channel for good: members go to the default channel, voice
+/// sessions in it end and it is removed from the channels file.
+fn handle_delete_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let Some(channel) = check_removable_channel(stream, server, parts, username)? else {
+        return Ok(());
+    };
+
+    // Delete and persist before touching anyone, so a failed save leaves
+    // the channel and its members exactly as they were
+    server.channel_manager.write()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .delete_channel(channel)?;
+
+    let notice = format!("*** {} was deleted; you have been moved to {} ***\n", channel, server.config.default_channel);
+    let moved = move_to_default_channel(server, channel, &notice)?;
+    let voice_users = server.voice_manager.lock()
+        .map_err(|_| "Failed to acquire voice manager lock")?
+        .end_channel_sessions(channel);
+    for user in &voice_users {
+        send_to_user(server, user, &format!("*** Voice channel {} was deleted ***\n", channel));
+    }
+
+    println!("{} deleted channel {}", username, channel);
+    notify_staff(server, &format!("{} deleted channel {} ({} users moved)", username, channel, moved));
+    stream.write_all(format!("Deleted channel {} ({} users moved to {})\n", channel, moved, server.config.default_channel).as_bytes())?;
+    Ok(())
+}
+
+/// Takes a channel out of listings and the channels file but lets its
+/// members stay; it is deleted once the last of them leaves.
+fn handle_hide_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    let Some(channel) = check_removable_channel(stream, server, parts, username)? else {
+        return Ok(());
+    };
+
+    server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .hide_channel(channel)?;
+
+    broadcast_to_channel(&server.clients, &server.channel_manager, channel,
+                         &format!("*** {} closed this channel; it will disappear once everyone has left ***\n", username), None);
+    println!("{} hid channel {}", username, channel);
+    notify_staff(server, &format!("{} hid channel {}", username, channel));
+    stream.write_all(format!("{} is hidden and no longer saved; it will be removed once empty\n", channel).as_bytes())?;
+    prune_hidden_channel(server, channel);
+    Ok(())
+}
+
+/// Shared checks for `/delete` and `/hide`: moderators only, and never the
+/// default or staff channel. Returns the channel name when it may be removed.
+fn check_removable_channel<'a>(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&'a str], username: &str) -> ServerResult<Option<&'a str>> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(None);
+    }
+
+    let Some(&channel) = parts.get(1) else {
+        write_hint(stream, server, &format!("Usage: {} <channel>\n", parts[0]))?;
+        return Ok(None);
+    };
+    if channel == server.config.default_channel || channel == STAFF_CHANNEL {
+        stream.write_all(format!("{} cannot be removed\n", channel).as_bytes())?;
+        return Ok(None);
+    }
+
+    let exists = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .get_channel(channel)
+        .is_some_and(|ch| !ch.hidden || parts[0] == "/delete");
+    if !exists {
+        stream.write_all(format!("Channel {} does not exist\n", channel).as_bytes())?;
+        return Ok(None);
+    }
+    Ok(Some(channel))
+}
+
+/// Moves every connection in a channel to the default channel, optionally
+/// locking it first so nobody rejoins during maintenance.
+fn handle_evacuate_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let (channel, lock) = match parts {
+        [_, channel] => (*channel, false),
+        [_, channel, "--lock"] => (*channel, true),
+        _ => {
+            write_hint(stream, server, "Usage: /evacuate <channel> [--lock]\n")?;
+            return Ok(());
+        }
+    };
+    let default_channel = &server.config.default_channel;
+    if channel == default_channel {
+        stream.write_all(b"The default channel cannot be evacuated\n")?;
+        return Ok(());
+    }
+
+    {
+        let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+        if !channel_manager.channel_exists(channel) {
+            stream.write_all(format!("Channel {} does not exist\n", channel).as_bytes())?;
+            return Ok(());
+        }
+        if lock {
+            channel_manager.set_locked(channel, true)?;
+        }
+    }
+
+    let notice = format!("*** {} is closed for maintenance; you have been moved to {} ***\n", channel, default_channel);
+    let moved = move_to_default_channel(server, channel, &notice)?;
+    prune_hidden_channel(server, channel);
+
+    let locked = if lock { " and locked it" } else { "" };
+    println!("{} evacuated {} ({} users){}", username, channel, moved, locked);
+    notify_staff(server, &format!("{} evacuated {} ({} users){}", username, channel, moved, locked));
+    stream.write_all(format!("Moved {} users from {} to {}{}\n", moved, channel, default_channel, locked).as_bytes())?;
+    Ok(())
+}
+
+/// Moves every connection whose current channel is `channel` to the default
+/// channel, sending each `notice`, and empties the channel's member list.
+/// Returns how many distinct users moved.
+fn move_to_default_channel(server: &Arc<Server>, channel: &str, notice: &str) -> ServerResult<usize> {
+    let default_channel = &server.config.default_channel;
+    let moved: Vec<Recipient> = {
+        let mut clients = server.clients.lock().map_err(|_| "Failed to acquire clients lock")?;
+        clients.values_mut()
+            .filter(|client| client.current_channel.as_deref() == Some(channel))
+            .filter_map(|client| {
+                client.current_channel = Some(default_channel.clone());
+                client.recipient()
+            })
+            .collect()
+    };
+
+    {
+        let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+        for client in &moved {
+            channel_manager.join_channel(default_channel, client.name.clone());
+        }
+        // Also drops members listed without a connection currently in the
+        // channel, so nobody is left behind in a locked or deleted channel
+        if let Some(mut ch) = channel_manager.get_channel(channel) {
+            ch.users.clear();
+        }
+    }
+
+    let mut users: Vec<String> = Vec::new();
+    for client in moved {
+        let result = client.send(notice.as_bytes());
+        record_write_result(&server.clients, &client, result);
+        publish_presence(server, "leave", &client.name, Some(channel), client.invisible);
+        publish_presence(server, "join", &client.name, Some(default_channel), client.invisible);
+
+        if !users.contains(&client.name) {
+            if !client.invisible {
+                broadcast_to_channel(&server.clients, &server.channel_manager, default_channel,
+                                     &format!("*** {} joined the channel ***\n", client.name), Some(client.id));
+            }
+            users.push(client.name);
+        }
+    }
+    Ok(users.len())
+}
+
+fn handle_summon_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let Some(target) = parts.get(1) else {
+        write_hint(stream, server, "Usage: /summon <user>\n")?;
+        return Ok(());
+    };
+
+    if send_to_user(server, target, &format!("*** {} is summoning you ***\n", username)) > 0 {
+        stream.write_all(format!("{} is online and has been notified\n", target).as_bytes())?;
+        return Ok(());
+    }
+
+    let exists = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .account_info(target)
+        .is_some();
+    if !exists {
+        stream.write_all(format!("User {} does not exist\n", target).as_bytes())?;
+        return Ok(());
+    }
+
+    let Some(url) = server.config.summon_webhook_url.clone() else {
+        stream.write_all(format!("{} is offline and no summon webhook is configured\n", target).as_bytes())?;
+        return Ok(());
+    };
+
+    webhook::post_json(url, webhook::SummonPayload {
+        user: target.to_string(),
+        by: username.to_string(),
+        server: server.config.server_name.clone(),
+    });
+    println!("{} summoned offline user {}", username, target);
+    notify_staff(server, &format!("{} summoned offline user {}", username, target));
+    stream.write_all(format!("{} is offline; summon notification sent\n", target).as_bytes())?;
+    Ok(())
+}
+
+fn handle_silence_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let (Some(target), Some(minutes)) = (parts.get(1), parts.get(2).and_then(|m| m.parse::<u64>().ok())) else {
+        write_hint(stream, server, "Usage: /silence <user> <minutes>\n")?;
+        return Ok(());
+    };
+    if !(1..=MAX_SILENCE_MINUTES).contains(&minutes) {
+        stream.write_all(format!("Minutes must be between 1 and {}\n", MAX_SILENCE_MINUTES).as_bytes())?;
+        return Ok(());
+    }
+
+    let target_role = {
+        let auth = server.auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+        auth.account_info(target).map(|info| info.role)
+    };
+    match target_role {
+        None => {
+            stream.write_all(format!("User {} does not exist\n", target).as_bytes())?;
+            return Ok(());
+        }
+        Some(role) if role.is_staff() => {
+            stream.write_all(b"Staff members cannot be silenced\n")?;
+            return Ok(());
+        }
+        Some(_) => {}
+    }
+
+    server.silences.lock()
+        .map_err(|_| "Failed to acquire silences lock")?
+        .insert(target.to_string(), Instant::now() + Duration::from_secs(minutes * 60));
+
+    println!("{} silenced {} for {} minutes", username, target, minutes);
+    notify_staff(server, &format!("{} silenced {} for {} minutes", username, target, minutes));
+    send_to_user(server, target, &format!("*** You have been silenced by {} for {} minutes ***\n", username, minutes));
+    stream.write_all(format!("{} is silenced for {} minutes\n", target, minutes).as_bytes())?;
+    Ok(())
+}
+
+fn handle_unsilence_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let Some(target) = parts.get(1) else {
+        write_hint(stream, server, "Usage: /unsilence <user>\n")?;
+        return Ok(());
+    };
+
+    let removed = server.silences.lock()
+        .map_err(|_| "Failed to acquire silences lock")?
+        .remove(*target)
+        .is_some_and(|expiry| expiry > Instant::now());
+
+    if removed {
+        notify_staff(server, &format!("{} lifted {}'s silence", username, target));
+        send_to_user(server, target, &format!("*** {} lifted your silence ***\n", username));
+        stream.write_all(format!("{} is no longer silenced\n", target).as_bytes())?;
+    } else {
+        stream.write_all(format!("{} is not silenced\n", target).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Account and connection details for moderators, including where each
+/// session connects from when a GeoIP database is configured.
+fn handle_whois_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let Some(target) = parts.get(1) else {
+        write_hint(stream, server, "Usage: /whois <user>\n")?;
+        return Ok(());
+    };
+
+    let Some(account) = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .account_info(target) else {
+        stream.write_all(format!("User {} does not exist\n", target).as_bytes())?;
+        return Ok(());
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let registered = account.created_at
+        .map(|created| format!("{} ago", format_elapsed(now.saturating_sub(created))))
+        .unwrap_or_else(|| "unknown".to_string());
+    let silenced = silence_remaining(server, target)
+        .map(|remaining| format!("{} minutes left", remaining.as_secs().div_ceil(60)))
+        .unwrap_or_else(|| "no".to_string());
+
+    let mut response = format!(
+        "=== {} ===\nDisplay name: {}\nRole: {}\nRegistered: {}\nLogins: {}\nSilenced: {}\n",
+        account.username, account.prefs.display_name.as_deref().unwrap_or("(none)"),
+        account.role, registered, account.login_count, silenced);
+
+    let sessions: Vec<(Option<IpAddr>, Option<String>, Duration, bool)> = server.clients.lock()
+        .map_err(|_| "Failed to acquire clients lock")?
+        .values()
+        .filter(|client| client.user.name == account.username)
+        .map(|client| (
+            client.stream.peer_addr().ok().map(|addr| addr.ip()),
+            client.current_channel.clone(),
+            client.last_activity.elapsed(),
+            client.invisible,
+        ))
+        .collect();
+
+    if sessions.is_empty() {
+        response.push_str("Sessions: offline\n");
+    } else {
+        response.push_str(&format!("Sessions: {}\n", sessions.len()));
+        for (ip, channel, idle, invisible) in sessions {
+            let address = match ip {
+                Some(ip) => match server.geoip.locate(ip) {
+                    Some(location) => format!("{} ({})", ip, location),
+                    None => ip.to_string(),
+                },
+                None => "unknown address".to_string(),
+            };
+            response.push_str(&format!("  {} - in {}, idle {}{}\n",
+                address,
+                channel.as_deref().unwrap_or("no channel"),
+                format_elapsed(idle.as_secs()),
+                if invisible { ", invisible" } else { "" }));
+        }
+    }
+
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// Short, stable handle for a connection; the full `Uuid` is too long to type.
+fn short_connection_id(id: Uuid) -> String {
+    id.simple().to_string()[..8].to_string()
+}
+
+fn handle_connections_command(stream: &mut ClientOutput, server: &Arc<Server>, username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_admin(server, username) {
+        stream.write_all(b"Only admins can use this command\n")?;
+        return Ok(());
+    }
+
+    let mut connections: Vec<(String, String)> = server.clients.lock()
+        .map_err(|_| "Failed to acquire clients lock")?
+        .values()
+        .map(|client| (
+            client.user.name.clone(),
+            format!("  {}  {} - {}, in {}, idle {}\n",
+                short_connection_id(client.id),
+                client.user.name,
+                client.stream.peer_addr().map(|addr| addr.ip().to_string())
+                    .unwrap_or_else(|_| "unknown address".to_string()),
+                client.current_channel.as_deref().unwrap_or("no channel"),
+                format_elapsed(client.last_activity.elapsed().as_secs())),
+        ))
+        .collect();
+    connections.sort();
+
+    let mut response = format!("=== Connections ({}) ===\n", connections.len());
+    for (_, line) in connections {
+        response.push_str(&line);
+    }
+    send_paged(stream, server, client_id, &response)
+}
+
+fn handle_disconnect_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_admin(server, username) {
+        stream.write_all(b"Only admins can use this command\n")?;
+        return Ok(());
+    }
+
+    let Some(id) = parts.get(1).map(|id| id.to_ascii_lowercase()) else {
+        write_hint(stream, server, "Usage: /disconnect <id>\n")?;
+        return Ok(());
+    };
+
+    let target = {
+        let clients_guard = server.clients.lock().map_err(|_| "Failed to acquire clients lock")?;
+        let matches: Vec<&Client> = clients_guard.values()
+            .filter(|client| client.id.simple().to_string().starts_with(&id))
+            .collect();
+        match matches.as_slice() {
+            [] => None,
+            [client] if client.id == client_id => {
+                write_hint(stream, server, "That is your own connection; use /quit\n")?;
+                return Ok(());
+            }
+            [client] => Some(client.try_clone()?),
+            _ => {
+                stream.write_all(b"That ID matches more than one connection\n")?;
+                return Ok(());
+            }
+        }
+    };
+
+    match target {
+        Some(mut target) => {
+            disconnect_client(&mut target, DisconnectReason::Disconnected);
+            let (id, name) = (short_connection_id(target.id), target.user.name);
+            println!("{} disconnected {} ({})", username, name, id);
+            stream.write_all(format!("Disconnected {} ({})\n", name, id).as_bytes())?;
+        }
+        None => stream.write_all(b"No connection with that ID\n")?,
+    }
+    Ok(())
+}
+
+fn handle_listsilences_command(stream: &mut ClientOutput, server: &Arc<Server>, username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let mut silenced: Vec<(String, Duration)> = {
+        let mut silences = server.silences.lock().map_err(|_| "Failed to acquire silences lock")?;
+        let now = Instant::now();
+        silences.retain(|_, expiry| *expiry > now);
+        silences.iter()
+            .map(|(user, expiry)| (user.clone(), *expiry - now))
+            .collect()
+    };
+
+    if silenced.is_empty() {
+        stream.write_all(b"No users are silenced\n")?;
+        return Ok(());
+    }
+
+    silenced.sort_by_key(|(_, remaining)| *remaining);
+    let mut response = format!("=== Silenced Users ===\n{:<32} {}\n", "User", "Remaining");
+    for (user, remaining) in silenced {
+        response.push_str(&format!("{:<32} {}m\n", user, remaining.as_secs().div_ceil(60)));
+    }
+    send_paged(stream, server, client_id, &response)
+}
+
+fn handle_say_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let mut args = command.splitn(3, char::is_whitespace).skip(1);
+    let (Some(channel_name), Some(text)) = (args.next(), args.next().map(str::trim)) else {
+        write_hint(stream, server, "Usage: /say <channel> <message>\n")?;
+        return Ok(());
+    };
+    if text.is_empty() {
+        write_hint(stream, server, "Usage: /say <channel> <message>\n")?;
+        return Ok(());
+    }
+    if contains_control_chars(text) {
+        stream.write_all(b"Message contains invalid characters\n")?;
+        return Ok(());
+    }
+
+    let exists = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .channel_exists(channel_name);
+    if !exists {
+        stream.write_all(format!("Channel {} does not exist\n", channel_name).as_bytes())?;
+        return Ok(());
+    }
+
+    broadcast_to_channel(&server.clients, &server.channel_manager, channel_name,
+                         &format!("[SERVER] {}\n", text), None);
+    stream.write_all(format!("Notice posted to {}\n", channel_name).as_bytes())?;
+    Ok(())
+}
+
+fn handle_spectate_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    if !is_staff(server, username) {
+        stream.write_all(b"Only moderators can use this command\n")?;
+        return Ok(());
+    }
+
+    let Some(channel_name) = parts.get(1) else {
+        write_hint(stream, server, "Usage: /spectate <channel>\n")?;
+        return Ok(());
+    };
+
+    let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+    match channel_manager.spectate(channel_name, client_id) {
+        Ok(()) => write_hint(stream, server, &format!("Spectating {}. Use /unspectate to stop.\n", channel_name))?,
+        Err(e) => stream.write_all(format!("{}\n", e).as_bytes())?,
+    }
+    Ok(())
+}
+
+fn handle_unspectate_command(stream: &mut ClientOutput, server: &Arc<Server>, client_id: Uuid) -> ServerResult<()> {
+    let channel_manager = server.channel_manager.read().map_err(|_| "Failed to acquire channel manager lock")?;
+    match channel_manager.unspectate(client_id) {
+        Some(channel) => stream.write_all(format!("Stopped spectating {}\n", channel).as_bytes())?,
+        None => stream.write_all(b"You're not spectating any channel\n")?,
+    }
+    Ok(())
+}
+
+fn handle_poll_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let args = command.strip_prefix("/poll").unwrap_or_default();
+    let mut fields = args.split('|').map(|f| f.trim().to_string());
+    let question = fields.next().unwrap_or_default();
+    let options: Vec<String> = fields.collect();
+
+    if question.is_empty() || options.is_empty() {
+        write_hint(stream, server, "Usage: /poll <question> | <opt1> | <opt2> ...\n")?;
+        return Ok(());
+    }
+    if contains_control_chars(&question) || options.iter().any(|option| contains_control_chars(option)) {
+        stream.write_all(b"Poll contains invalid characters\n")?;
+        return Ok(());
+    }
+
+    let Some(post) = admit_post(stream, server, username, client_id, |_| question.clone()) else {
+        return Ok(());
+    };
+
+    let announcement = {
+        let mut poll_manager = server.poll_manager.lock().map_err(|_| "Failed to acquire poll manager lock")?;
+        match poll_manager.create_poll(&post.channel, username, &question, options) {
+            Ok(poll) => poll.format_announcement(server.config.command_prefix()),
+            Err(e) => {
+                stream.write_all(format!("{}\n", e).as_bytes())?;
+                return Ok(());
+            }
+        }
+    };
+
+    broadcast_to_channel(&server.clients, &server.channel_manager, &post.channel, &announcement, None);
+    announce_slow_mode_change(server, &post);
+    Ok(())
+}
+
+fn parse_poll_id(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], usage: &str) -> ServerResult<Option<u32>> {
+    match parts.get(1).and_then(|id| id.trim_start_matches('#').parse().ok()) {
+        Some(id) => Ok(Some(id)),
+        None => {
+            write_hint(stream, server, &format!("Usage: {}\n", usage))?;
+            Ok(None)
+        }
+    }
+}
+
+fn handle_vote_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    let usage = "/vote <poll_id> <option>";
+    let Some(poll_id) = parse_poll_id(stream, server, parts, usage)? else {
+        return Ok(());
+    };
+    let Some(option) = parts.get(2).and_then(|o| o.parse::<usize>().ok()) else {
+        write_hint(stream, server, &format!("Usage: {}\n", usage))?;
+        return Ok(());
+    };
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    let mut poll_manager = server.poll_manager.lock().map_err(|_| "Failed to acquire poll manager lock")?;
+    match poll_manager.vote(&channel, poll_id, username, option) {
+        Ok(()) => stream.write_all(format!("Vote recorded for option {} in poll #{}\n", option, poll_id).as_bytes())?,
+        Err(e) => stream.write_all(format!("{}\n", e).as_bytes())?,
+    }
+    Ok(())
+}
+
+fn handle_pollresults_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], client_id: Uuid) -> ServerResult<()> {
+    let Some(poll_id) = parse_poll_id(stream, server, parts, "/pollresults <poll_id>")? else {
+        return Ok(());
+    };
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    let poll_manager = server.poll_manager.lock().map_err(|_| "Failed to acquire poll manager lock")?;
+    match poll_manager.get_poll(&channel, poll_id) {
+        Some(poll) => stream.write_all(poll.format_results(poll_manager.duration()).as_bytes())?,
+        None => stream.write_all(format!("No poll #{} in this channel\n", poll_id).as_bytes())?,
+    }
+    Ok(())
+}
+
+fn handle_pollclose_command(stream: &mut ClientOutput, server: &Arc<Server>, parts: &[&str], username: &str, client_id: Uuid) -> ServerResult<()> {
+    let Some(poll_id) = parse_poll_id(stream, server, parts, "/pollclose <poll_id>")? else {
+        return Ok(());
+    };
+
+    let Some(channel) = get_client_current_channel(&server.clients, client_id) else {
+        stream.write_all(b"You're not in any channel\n")?;
+        return Ok(());
+    };
+
+    let results = {
+        let mut poll_manager = server.poll_manager.lock().map_err(|_| "Failed to acquire poll manager lock")?;
+        let duration = poll_manager.duration();
+        match poll_manager.close_poll(&channel, poll_id, username) {
+            Ok(poll) => poll.format_results(duration),
+            Err(e) => {
+                stream.write_all(format!("{}\n", e).as_bytes())?;
+                return Ok(());
+            }
+        }
+    };
+
+    broadcast_to_channel(&server.clients, &server.channel_manager, &channel,
+                         &format!("*** {} closed poll #{} ***\n{}", username, poll_id, results), None);
+    Ok(())
+}
+
+fn handle_suggest_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str) -> ServerResult<()> {
+    let interest = command.split_once(char::is_whitespace)
+        .map(|(_, rest)| rest.trim())
+        .unwrap_or("");
+    if interest.is_empty() {
+        write_hint(stream, server, "Usage: /suggest <interest>\n")?;
+        return Ok(());
+    }
+
+    let show_staff = is_staff(server, username);
+    let suggestions: Vec<_> = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .suggest_channels(interest, MAX_SUGGESTIONS + 1)
+        .into_iter()
+        .filter(|(name, _, _, _)| show_staff || name != STAFF_CHANNEL)
+        .take(MAX_SUGGESTIONS)
+        .collect();
+
+    if suggestions.is_empty() {
+        stream.write_all(b"No matching channels\n")?;
+        return Ok(());
+    }
+
+    let mut response = format!("=== Channels for '{}' ===\n", interest);
+    for (name, channel_type, user_count, welcome) in suggestions {
+        response.push_str(&format!("{} {} ({} users)", channel_icon(&channel_type), name, user_count));
+        if let Some(welcome) = welcome {
+            response.push_str(&format!(" - {}", welcome));
+        }
+        response.push('\n');
+    }
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+fn handle_search_command(stream: &mut ClientOutput, server: &Arc<Server>, command: &str, username: &str, client_id: Uuid) -> ServerResult<()> {
+    let term = command.split_once(char::is_whitespace)
+        .map(|(_, rest)| rest.trim())
+        .unwrap_or("");
+    if term.is_empty() {
+        write_hint(stream, server, "Usage: /search <term>\n")?;
+        return Ok(());
+    }
+
+    let show_staff = is_staff(server, username);
+    let results: Vec<_> = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?
+        .search_channels(term, MAX_SEARCH_RESULTS + 1)
+        .into_iter()
+        .filter(|(name, _, _)| show_staff || name != STAFF_CHANNEL)
+        .take(MAX_SEARCH_RESULTS)
+        .collect();
+
+    if results.is_empty() {
+        stream.write_all(format!("No channels match '{}'\n", term).as_bytes())?;
+        return Ok(());
+    }
+
+    let mut response = format!("=== Channels matching '{}' ===\n", term);
+    for (name, channel_type, user_count) in results {
+        response.push_str(&format!("{} {} ({} users)\n", channel_icon(&channel_type), name, user_count));
+    }
+    send_paged(stream, server, client_id, &response)
+}
+
+fn channel_icon(channel_type: &ChannelType) -> &'static str {
+    match channel_type {
+        ChannelType::Text => "📝",
+        ChannelType::Voice => "🔊",
+    }
+}
+
+fn show_channels(stream: &mut ClientOutput, server: &Arc<Server>, username: &str) -> ServerResult<()> {
+    stream.write_all(format_channel_list(server, username, false)?.as_bytes())?;
+    Ok(())
+}
+
+/// With `preview`, each channel's latest line (truncated to `PREVIEW_LENGTH`
+/// characters) and its age are appended, for channels that have one.
+fn format_channel_list(server: &Arc<Server>, username: &str, preview: bool) -> ServerResult<String> {
+    let show_staff = is_staff(server, username);
+    let manager = server.channel_manager.read()
+        .map_err(|_| "Failed to acquire channel manager lock")?;
+
+    let mut response = String::from("\n=== Available Channels ===\n");
+    for (name, channel_type, user_count, locked) in manager.list_channels() {
+        if name == STAFF_CHANNEL && !show_staff {
+            continue;
+        }
+        let lock = if locked { " [locked]" } else { "" };
+        response.push_str(&format!("{} {} ({} users){}", channel_icon(&channel_type), name, user_count, lock));
+        if preview
+            && let Some((message, sent)) = manager.get_channel(&name).and_then(|ch| ch.last_message.clone()) {
+            let mut text: String = message.chars().take(PREVIEW_LENGTH).collect();
+            if message.chars().count() > PREVIEW_LENGTH {
+                text.push('…');
+            }
+            response.push_str(&format!(" — \"{}\" ({} ago)", text, format_elapsed(sent.elapsed().as_secs())));
+        }
+        response.push('\n');
+    }
+    response.push_str("========================\n");
+    Ok(response)
+}
+
+/// Writes `text`, holding back everything past the first `page_lines` lines
+/// for `/more`. A new paged response replaces any unread pages.
+fn send_paged(stream: &mut ClientOutput, server: &Arc<Server>, client_id: Uuid, text: &str) -> ServerResult<()> {
+    let page_lines = server.config.page_lines;
+    let mut lines: VecDeque<String> = text.lines().map(str::to_string).collect();
+    if page_lines == 0 || lines.len() <= page_lines {
+        stream.write_all(text.as_bytes())?;
+        return Ok(());
+    }
+
+    let first_page: Vec<String> = lines.drain(..page_lines).collect();
+    let remaining = lines.len();
+    if let Ok(mut clients) = server.clients.lock()
+        && let Some(client) = clients.get_mut(&client_id) {
+        client.paged_output = lines;
+    }
+
+    stream.write_all(format!("{}\n--- type {}more for next page ({} remaining) ---\n",
+                             first_page.join("\n"), server.config.command_prefix(), remaining).as_bytes())?;
+    Ok(())
+}
+
+fn send_next_page(stream: &mut ClientOutput, server: &Arc<Server>, client_id: Uuid) -> ServerResult<()> {
+    let page_lines = server.config.page_lines.max(1);
+    let (page, remaining) = {
+        let mut clients = server.clients.lock().map_err(|_| "Failed to acquire clients lock")?;
+        let Some(client) = clients.get_mut(&client_id) else {
+            return Ok(());
+        };
+        let take = page_lines.min(client.paged_output.len());
+        let page: Vec<String> = client.paged_output.drain(..take).collect();
+        (page, client.paged_output.len())
+    };
+
+    if page.is_empty() {
+        stream.write_all(b"Nothing more to show\n")?;
+        return Ok(());
+    }
+
+    let mut output = page.join("\n");
+    output.push('\n');
+    if remaining > 0 {
+        output.push_str(&format!("--- type {}more for next page ({} remaining) ---\n", server.config.command_prefix(), remaining));
+    }
+    stream.write_all(output.as_bytes())?;
+    Ok(())
+}
+
+fn broadcast_to_channel(clients: &Arc<Mutex<HashMap<Uuid, Client>>>,
+                        channel_manager: &Arc<RwLock<ChannelManager>>,
+                        channel_name: &str,
+                        message: &str,
+                        exclude_client_id: Option<Uuid>) {
+    broadcast_rendered(clients, channel_manager, channel_name, exclude_client_id,
+                       |_| Some(Cow::Borrowed(message)));
+}
+
+/// Broadcasts a user's chat line, formatting the body per recipient and
+/// skipping recipients in mentions-only mode who are not mentioned.
+fn broadcast_chat_message(clients: &Arc<Mutex<HashMap<Uuid, Client>>>,
+                          channel_manager: &Arc<RwLock<ChannelManager>>,
+                          channel_name: &str,
+                          username: &str,
+                          body: &str,
+                          exclude_client_id: Option<Uuid>) {
+    let plain = format!("[{}] {}: {}\n", channel_name, username, body);
+    let with_emoji = emoji::expand_shortcodes(body);
+    let emoji_line = format!("[{}] {}: {}\n", channel_name, username, with_emoji);
+    broadcast_rendered(clients, channel_manager, channel_name, exclude_client_id, |client| {
+        if client.mentions_only.contains(channel_name) && !mentions(body, &client.name) {
+            return None;
+        }
+        let body = if client.emoji { with_emoji.as_ref() } else { body };
+        let sender = client.color_scheme.sender(username);
+        if client.markdown {
+            Some(Cow::Owned(format!("[{}] {}: {}\n", channel_name, sender, format::render_markdown(body, client.color_scheme))))
+        } else if matches!(sender, Cow::Owned(_)) {
+            Some(Cow::Owned(format!("[{}] {}: {}\n", channel_name, sender, body)))
+        } else if client.emoji {
+            Some(Cow::Borrowed(emoji_line.as_str()))
+        } else {
+            Some(Cow::Borrowed(plain.as_str()))
+        }
+    });
+}
+
+/// Whether `text` contains `@username` as a whole word (case-insensitive).
+fn mentions(text: &str, username: &str) -> bool {
+    text.match_indices('@').any(|(at, _)| {
+        let rest = &text[at + 1..];
+        rest.get(..username.len()).is_some_and(|name| name.eq_ignore_ascii_case(username))
+            && rest[username.len()..].chars().next()
+                .is_none_or(|c| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+    })
+}
+
+fn broadcast_rendered<'a, F>(clients: &Arc<Mutex<HashMap<Uuid, Client>>>,
+                             channel_manager: &Arc<RwLock<ChannelManager>>,
+                             channel_name: &str,
+                             exclude_client_id: Option<Uuid>,
+                             render: F)
+where
+    F: Fn(&Recipient) -> Option<Cow<'a, str>>,
+{
+    // Get channel users and spectators
+    let (channel_users, observers) = if let Ok(manager) = channel_manager.read() {
+        manager.get_channel(channel_name)
+            .map(|ch| (ch.member_names(), ch.observers.clone()))
+            .unwrap_or_default()
+    } else {
+        return;
+    };
+
+    // Get clients to broadcast to
+    let clients_to_notify: Vec<Recipient> = if let Ok(clients_guard) = clients.lock() {
+        clients_guard.values()
+            .filter(|client| {
+                (channel_users.contains(&client.user.name) || observers.contains(&client.id)) &&
+                (exclude_client_id != Some(client.id))
+            })
+            .filter_map(Client::recipient)
+            .collect()
+    } else {
+        return;
+    };
+
+    // Send messages (no locks held)
+    for client in clients_to_notify {
+        let Some(message) = render(&client) else {
+            continue;
+        };
+        let result = client.send(message.as_bytes());
+        record_write_result(clients, &client, result);
+    }
+}
+
+/// Sends a message to every connection of `username`, returning how many received it.
+fn send_to_user(server: &Arc<Server>, username: &str, message: &str) -> usize {
+    let recipients: Vec<Recipient> = match server.clients.lock() {
+        Ok(clients) => clients.values()
+            .filter(|client| client.user.name == username)
+            .filter_map(Client::recipient)
+            .collect(),
+        Err(_) => return 0,
+    };
+
+    let count = recipients.len();
+    for client in recipients {
+        let result = client.send(message.as_bytes());
+        record_write_result(&server.clients, &client, result);
+    }
+    count
+}
+
+/// Tracks consecutive write failures, removing a client after a hard error
+/// or once transient failures pile up.
+fn record_write_result(clients: &Arc<Mutex<HashMap<Uuid, Client>>>, client: &Recipient, result: std::io::Result<()>) {
+    match result {
+        Ok(()) if client.write_failures == 0 => {}
+        Ok(()) => {
+            if let Ok(mut clients_guard) = clients.lock()
+                && let Some(entry) = clients_guard.get_mut(&client.id) {
+                entry.write_failures = 0;
+            }
+        }
+        Err(e) => {
+            if let Ok(mut clients_guard) = clients.lock() {
+                let failures = match clients_guard.get_mut(&client.id) {
+                    Some(entry) => {
+                        entry.write_failures += 1;
+                        entry.write_failures
+                    }
+                    None => return,
+                };
+
+                if !client::is_transient(&e) || failures >= MAX_WRITE_FAILURES {
+                    // Remove failed client
+                    clients_guard.remove(&client.id);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the optional connect banner, truncated to `MAX_BANNER_SIZE` bytes.
+/// It is re-read per connection so operators can edit it without a restart.
+fn load_banner() -> Option<Vec<u8>> {
+    let file = std::fs::File::open(BANNER_FILE).ok()?;
+    let mut banner = Vec::new();
+    file.take(MAX_BANNER_SIZE).read_to_end(&mut banner).ok()?;
+    if banner.is_empty() {
+        return None;
+    }
+    if !banner.ends_with(b"\n") {
+        banner.push(b'\n');
+    }
+    Some(banner)
+}
+
+fn authenticate_client(stream: &mut TcpStream, reader: &mut LineReader, server: &Arc<Server>) -> ServerResult<user::User> {
+    let prefix = server.config.command_prefix();
+    if let Some(banner) = load_banner() {
+        stream.write_all(&banner)?;
+    }
+    stream.write_all(b"Welcome to the chat server!\n")?;
+    stream.write_all(b"1. Login\n2. Register\n")?;
+
+    let choice = read_auth_input(stream, reader, "Choose option (1 or 2): ", prefix)?;
+
+    match choice.as_str() {
+        "1" => login_user(stream, reader, &server.auth_manager, prefix),
+        "2" => register_user(stream, reader, server, prefix),
+        _ => {
+            stream.write_all(b"Invalid choice.\n")?;
+            Err("Invalid authentication choice".into())
+        }
+    }
+}
+
+fn login_user(stream: &mut TcpStream, reader: &mut LineReader, auth_manager: &Arc<Mutex<AuthManager>>, prefix: &str) -> ServerResult<user::User> {
+    let username = read_auth_input(stream, reader, "Username: ", prefix)?;
+
+    stream.write_all(b"Password: ")?;
+    let password = read_line(stream, reader)?;
+
+    let auth = auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
+    match auth.login(&username, &password) {
         Ok(user) => {
             stream.write_all(b"Login successful!\n")?;
+            if !auth.is_verified(&user.name) {
+                stream.write_all(format!("Your email is not verified yet; enter the emailed code with {}verify <code>\n", prefix).as_bytes())?;
+            }
             Ok(user)
         }
         Err(e) => {
@@ -482,74 +3757,363 @@ fn login_user(stream: &mut TcpStream, auth_manager: &Arc<Mutex<AuthManager>>) ->
     }
 }
 
-fn register_user(stream: &mut TcpStream, auth_manager: &Arc<Mutex<AuthManager>>) -> ServerResult<user::User> {
-    stream.write_all(b"Choose username: ")?;
-    let username = read_line(stream)?;
+fn register_user(stream: &mut TcpStream, reader: &mut LineReader, server: &Arc<Server>, prefix: &str) -> ServerResult<user::User> {
+    let username = read_auth_input(stream, reader, "Choose username: ", prefix)?;
 
     stream.write_all(b"Choose password: ")?;
-    let password = read_line(stream)?;
+    let password = read_line(stream, reader)?;
 
-    let mut auth = auth_manager.lock().map_err(|_| "Failed to acquire auth manager lock")?;
-    match auth.register(&username, &password) {
-        Ok(user) => {
-            stream.write_all(b"Registration successful! You are now logged in.\n")?;
-            Ok(user)
+    let address = if server.config.require_email {
+        let address = read_auth_input(stream, reader, "Email: ", prefix)?;
+        if !email::is_valid_email(&address) {
+            stream.write_all(b"Registration failed: Invalid email address\n")?;
+            return Err("Invalid email address".into());
         }
+        Some(address)
+    } else {
+        None
+    };
+    let code = address.as_ref()
+        .filter(|_| server.mailer.is_enabled())
+        .map(|_| email::verification_code());
+
+    let result = server.auth_manager.lock()
+        .map_err(|_| "Failed to acquire auth manager lock")?
+        .register(&username, &password, address.as_deref().map(|address| RegistrationEmail {
+            address,
+            hashed: server.config.hash_emails,
+            verification_code: code.clone(),
+        }));
+    let user = match result {
+        Ok(user) => user,
         Err(e) => {
             stream.write_all(format!("Registration failed: {}\n", e).as_bytes())?;
-            Err(e.into())
+            return Err(e.into());
+        }
+    };
+    stream.write_all(b"Registration successful! You are now logged in.\n")?;
+
+    if let (Some(address), Some(code)) = (address, code) {
+        match server.mailer.send_verification_code(&address, &server.config.server_name, &username, &code, prefix) {
+            Ok(()) => stream.write_all(format!(
+                "A verification code was sent to {}; enter it with {}verify <code>. Until then you can't create channels.\n",
+                address, prefix).as_bytes())?,
+            Err(e) => {
+                eprintln!("Verification email to {} for {} failed: {}", address, username, e);
+                stream.write_all(format!("The verification email couldn't be sent; try {}verify resend later\n", prefix).as_bytes())?;
+            }
         }
     }
+    Ok(user)
 }
 
-fn read_line(stream: &mut TcpStream) -> ServerResult<String> {
-    let mut buffer = vec![0u8; BUFFER_SIZE];
-    let n = stream.read(&mut buffer)?;
-    if n == 0 {
-        return Err("Connection closed".into());
+/// Prompts for a menu choice or username, answering the few commands that
+/// work before login and re-prompting after them. Passwords are read with
+/// plain `read_line` so one starting with the prefix is never mistaken for a command.
+fn read_auth_input(stream: &mut TcpStream, reader: &mut LineReader, prompt: &str, prefix: &str) -> ServerResult<String> {
+    loop {
+        stream.write_all(prompt.as_bytes())?;
+        let line = read_line(stream, reader)?;
+        let command = match classify_input(&line, prefix) {
+            Input::Chat(text) => return Ok(text.to_string()),
+            Input::Command(command) => command,
+        };
+
+        match command.split_whitespace().next().unwrap_or_default() {
+            "/help" => stream.write_all(with_command_prefix(PRE_AUTH_HELP_MESSAGE, prefix).as_bytes())?,
+            "/version" => stream.write_all(format!("ChatServer {}\n", env!("CARGO_PKG_VERSION")).as_bytes())?,
+            "/quit" => {
+                stream.write_all(format!("Goodbye!\n{}", DisconnectReason::Quit.line()).as_bytes())?;
+                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "Client quit").into());
+            }
+            _ => stream.write_all(b"Please log in first\n")?,
+        }
     }
-    Ok(String::from_utf8_lossy(&buffer[..n]).trim().to_string())
 }
 
-fn main() -> ServerResult<()> {
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
-    println!("Server listening on 127.0.0.1:8080");
+fn read_line(stream: &mut TcpStream, reader: &mut LineReader) -> ServerResult<String> {
+    match reader.read_line(stream)? {
+        Some(Line::Complete(line)) => Ok(line.trim().to_string()),
+        Some(Line::TooLong) => {
+            stream.write_all(b"Input too long\n")?;
+            Err("Input too long".into())
+        }
+        Some(Line::Invalid) => {
+            stream.write_all(b"Invalid input\n")?;
+            Err("Invalid input".into())
+        }
+        Some(Line::InvalidUtf8) => {
+            stream.write_all(b"Invalid UTF-8 in input\n")?;
+            Err("Invalid UTF-8 in input".into())
+        }
+        None => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Connection closed").into()),
+    }
+}
+
+/// Whether an error means the peer went away, as opposed to a failure worth reporting to it.
+fn is_disconnect(error: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    use std::io::ErrorKind;
+    error.downcast_ref::<std::io::Error>().is_some_and(|e| matches!(e.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted))
+}
+
+fn is_timeout(error: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    use std::io::ErrorKind;
+    error.downcast_ref::<std::io::Error>().is_some_and(|e| matches!(e.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+/// Half-closes the connection so everything already written is delivered,
+/// then drains any unread input until the peer closes or `CLOSE_LINGER`
+/// passes; closing with unread input would reset the connection and could
+/// discard the final lines before the client reads them.
+fn close_gracefully(stream: &mut TcpStream) {
+    let _ = stream.flush();
+    if stream.shutdown(std::net::Shutdown::Write).is_err() {
+        return;
+    }
+    let _ = stream.set_read_timeout(Some(CLOSE_LINGER));
+    let deadline = Instant::now() + CLOSE_LINGER;
+    let mut buf = [0u8; 1024];
+    while Instant::now() < deadline {
+        match stream.read(&mut buf) {
+            Ok(n) if n > 0 => {}
+            _ => break,
+        }
+    }
+}
+
+/// Answers every connection on the health port with `OK` and closes it,
+/// without authentication and without counting against connection limits.
+fn spawn_health_check_listener(port: u16) -> ServerResult<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Health check listening on 127.0.0.1:{}", port);
 
-    let (server, _shutdown_rx) = Server::new();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    let _ = stream.write_all(b"OK\n");
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                }
+                Err(e) => eprintln!("Health check connection failed: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Deletes expired channel logs once at startup and then every `LOG_SWEEP_INTERVAL`.
+fn spawn_log_retention_sweep(server: Arc<Server>, retention: Duration) {
+    thread::spawn(move || loop {
+        match server.message_logger.lock() {
+            Ok(logger) => match logger.remove_older_than(retention) {
+                Ok(0) => {}
+                Ok(removed) => println!("Removed {} expired log files", removed),
+                Err(e) => eprintln!("Log retention sweep failed: {}", e),
+            },
+            Err(_) => return,
+        }
+        thread::sleep(LOG_SWEEP_INTERVAL);
+    });
+}
+
+/// Accepts `POST /message` requests carrying `Authorization: Bearer <token>`
+/// and posts their text into a channel as a bot user. This is its own port
+/// rather than the health port, which answers anyone with `OK` without
+/// reading a request so probes stay cheap.
+fn spawn_webhook_listener(server: Arc<Server>, port: u16, token: String) -> ServerResult<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Incoming webhooks listening on 127.0.0.1:{}", port);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    let server = Arc::clone(&server);
+                    let token = token.clone();
+                    thread::spawn(move || {
+                        let _ = stream.set_read_timeout(Some(WEBHOOK_READ_TIMEOUT));
+                        let _ = stream.set_write_timeout(Some(WEBHOOK_READ_TIMEOUT));
+                        let (status, body) = handle_webhook_request(&mut stream, &server, &token);
+                        webhook::respond(&mut stream, status, &body);
+                    });
+                }
+                Err(e) => eprintln!("Webhook connection failed: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_webhook_request(stream: &mut TcpStream, server: &Arc<Server>, token: &str) -> (&'static str, String) {
+    let request = match webhook::read_request(stream) {
+        Ok(request) => request,
+        Err(status) => return (status, String::new()),
+    };
+
+    if request.path != "/message" {
+        return ("404 Not Found", String::new());
+    }
+    if request.method != "POST" {
+        return ("405 Method Not Allowed", String::new());
+    }
+    let presented = request.authorization.as_deref().and_then(|auth| auth.strip_prefix("Bearer "));
+    if !presented.is_some_and(|presented| password::constant_time_eq(presented.as_bytes(), token.as_bytes())) {
+        return ("401 Unauthorized", String::new());
+    }
+
+    let message: webhook::IncomingMessage = match serde_json::from_slice(&request.body) {
+        Ok(message) => message,
+        Err(e) => return ("400 Bad Request", format!("Invalid JSON: {}\n", e)),
+    };
+
+    let text = message.text.trim();
+    if text.is_empty() || text.chars().count() > MAX_WEBHOOK_TEXT_LENGTH {
+        return ("400 Bad Request", format!("Text must be 1 to {} characters\n", MAX_WEBHOOK_TEXT_LENGTH));
+    }
+    if contains_control_chars(text) {
+        return ("400 Bad Request", "Text contains invalid characters\n".to_string());
+    }
+
+    let bot_name = message.username.as_deref().unwrap_or("webhook");
+    if bot_name.is_empty() || bot_name.len() > 32
+        || !bot_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return ("400 Bad Request", "Invalid username\n".to_string());
+    }
+
+    // Webhooks post like staff: announcement channels accept them, but the
+    // staff channel stays out of reach
+    let is_text_channel = message.channel != STAFF_CHANNEL && server.channel_manager.read()
+        .map(|manager| manager.get_channel(&message.channel)
+            .is_some_and(|channel| channel.channel_type == ChannelType::Text))
+        .unwrap_or(false);
+    if !is_text_channel {
+        return ("404 Not Found", format!("Text channel {} does not exist\n", message.channel));
+    }
+
+    let sender = format!("{} [bot]", bot_name);
+    match broadcast_admission(server, &message.channel, text) {
+        Admission::Send => {}
+        Admission::Repeated => return ("429 Too Many Requests", "The same line was just sent here several times\n".to_string()),
+        Admission::OverBudget => return ("429 Too Many Requests", "Server is busy; try again shortly\n".to_string()),
+    }
+    broadcast_chat_message(&server.clients, &server.channel_manager, &message.channel, &sender, text, None);
+    log_channel_message(server, &message.channel, &sender, text);
+    ("200 OK", "Posted\n".to_string())
+}
+
+/// Binds the chat listener with `SO_REUSEADDR` so a quick restart does not
+/// fail on sockets still in TIME_WAIT, using the configured accept backlog.
+fn bind_listener(addr: SocketAddr, backlog: i32) -> ServerResult<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(socket.into())
+}
+
+fn main() -> ServerResult<()> {
+    let (server, shutdown_rx) = Server::new();
     let server = Arc::new(server);
 
+    let addr: SocketAddr = "127.0.0.1:8080".parse()?;
+    let listener = bind_listener(addr, server.config.listen_backlog)?;
+    println!("Server listening on {} (backlog {})", addr, server.config.listen_backlog);
+
+    if let Some(port) = server.config.health_check_port {
+        spawn_health_check_listener(port)?;
+    }
+
+    match (server.config.webhook_port, server.config.webhook_token.clone()) {
+        (Some(port), Some(token)) if !token.is_empty() => spawn_webhook_listener(Arc::clone(&server), port, token)?,
+        (Some(_), _) => eprintln!("webhook_port is set without a webhook_token; incoming webhooks disabled"),
+        _ => {}
+    }
+
+    if server.config.log_messages && let Some(retention) = server.config.log_retention() {
+        spawn_log_retention_sweep(Arc::clone(&server), retention);
+    }
+
     // Setup signal handling for graceful shutdown
     ctrlc::set_handler({
         let server = server.clone();
         move || {
             println!("\nShutting down server...");
-            let _ = server.shutdown_tx.send(());
+            let _ = server.shutdown_tx.send(0);
         }
     }).expect("Error setting Ctrl-C handler");
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                if !server.can_accept_connection() {
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                    continue;
-                }
+    // Accept on a separate thread so the main thread is free to wait for a shutdown request
+    thread::spawn({
+        let server = Arc::clone(&server);
+        move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if !server.can_accept_connection() {
+                            let _ = (&stream).write_all(format!("Server is full\n{}", DisconnectReason::ServerFull.line()).as_bytes());
+                            let _ = stream.shutdown(std::net::Shutdown::Both);
+                            continue;
+                        }
 
-                if !server.increment_connection_count() {
-                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                    continue;
-                }
+                        let ip = match stream.peer_addr() {
+                            Ok(addr) => addr.ip(),
+                            Err(e) => {
+                                eprintln!("Failed to get peer address: {}", e);
+                                continue;
+                            }
+                        };
+
+                        if !server.increment_ip_connection_count(ip) {
+                            let mut stream = stream;
+                            let _ = stream.write_all(b"Too many connections from your address\n");
+                            let _ = stream.write_all(DisconnectReason::TooManyConnections.line().as_bytes());
+                            let _ = stream.shutdown(std::net::Shutdown::Both);
+                            continue;
+                        }
+
+                        if !server.increment_connection_count() {
+                            server.decrement_ip_connection_count(ip);
+                            let _ = (&stream).write_all(format!("Server is full\n{}", DisconnectReason::ServerFull.line()).as_bytes());
+                            let _ = stream.shutdown(std::net::Shutdown::Both);
+                            continue;
+                        }
 
-                let server_clone = Arc::clone(&server);
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, server_clone) {
-                        eprintln!("Client handling error: {}", e);
+                        let guard = ConnectionGuard { server: Arc::clone(&server), ip };
+                        thread::spawn(move || {
+                            if let Err(e) = handle_client(stream, Arc::clone(&guard.server)) {
+                                eprintln!("Client handling error: {}", e);
+                            }
+                            drop(guard);
+                        });
                     }
-                });
+                    Err(e) => eprintln!("Connection failed: {}", e),
+                }
             }
-            Err(e) => eprintln!("Connection failed: {}", e),
+
         }
+    });
+
+    let exit_code = shutdown_rx.recv().unwrap_or(0);
+    shut_down(&server, exit_code)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countdown_announces_each_step_once() {
+        assert_eq!(countdown_steps(10), vec![(10, 5), (5, 5)]);
+        assert_eq!(countdown_steps(60), vec![(60, 30), (30, 20), (10, 5), (5, 5)]);
+        assert_eq!(countdown_steps(7), vec![(7, 2), (5, 5)]);
+        assert_eq!(countdown_steps(3), vec![(3, 3)]);
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn countdown_without_delay_is_empty() {
+        assert!(countdown_steps(0).is_empty());
+    }
+}
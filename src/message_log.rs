@@ -0,0 +1,342 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct LoggedMessage {
+    pub channel: String,
+    pub timestamp: u64,
+    pub text: String,
+}
+
+/// Appends channel messages to one `<channel>.log` file per channel.
+///
+/// A log that grows past `max_bytes` is rotated to `<channel>.log.1`, older
+/// rotations shifting up to `.2`, `.3` and so on. Rotation happens inside
+/// `log_message`, which callers serialize through the logger's lock, so no
+/// write can land in a file while it is being renamed.
+pub struct MessageLogger {
+    enabled: bool,
+    log_dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl MessageLogger {
+    /// A `max_bytes` of 0 disables rotation.
+    pub fn new(enabled: bool, log_dir: &str, max_bytes: u64) -> Self {
+        MessageLogger {
+            enabled,
+            log_dir: PathBuf::from(log_dir),
+            max_bytes,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn log_message(&self, channel: &str, username: &str, message: &str) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.log_dir)
+            .map_err(|e| format!("Failed to create log directory: {}", e))?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path(channel))
+            .map_err(|e| format!("Failed to open log file: {}", e))?;
+
+        writeln!(file, "[{}] {}: {}", timestamp, username, message)
+            .map_err(|e| format!("Failed to write log file: {}", e))?;
+
+        let size = file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+        drop(file);
+        if self.max_bytes > 0 && size > self.max_bytes {
+            self.rotate(channel)?;
+        }
+        Ok(())
+    }
+
+    /// Renames `<channel>.log` to `<channel>.log.1`, shifting existing rotations up by one.
+    fn rotate(&self, channel: &str) -> Result<(), String> {
+        let path = self.log_path(channel);
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+
+        let mut highest = 0;
+        while rotated(highest + 1).exists() {
+            highest += 1;
+        }
+        for n in (1..=highest).rev() {
+            fs::rename(rotated(n), rotated(n + 1))
+                .map_err(|e| format!("Failed to rotate log file: {}", e))?;
+        }
+        fs::rename(&path, rotated(1))
+            .map_err(|e| format!("Failed to rotate log file: {}", e))
+    }
+
+    /// Deletes current and rotated log files not written to within `max_age`,
+    /// returning how many were removed.
+    pub fn remove_older_than(&self, max_age: Duration) -> Result<usize, String> {
+        if !self.log_dir.exists() {
+            return Ok(0);
+        }
+
+        let entries = fs::read_dir(&self.log_dir)
+            .map_err(|e| format!("Failed to read log directory: {}", e))?;
+
+        let mut removed = 0;
+        for entry in entries.flatten() {
+            let is_log = channel_of(&entry.file_name().to_string_lossy()).is_some();
+            let expired = entry.metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > max_age);
+
+            if is_log && expired && fs::remove_file(entry.path()).is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Collects the most recent logged messages written by `username` across
+    /// all channel logs, rotations included.
+    pub fn messages_by(&self, username: &str, limit: usize) -> Result<Vec<LoggedMessage>, String> {
+        if !self.enabled || !self.log_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let entries = fs::read_dir(&self.log_dir)
+            .map_err(|e| format!("Failed to read log directory: {}", e))?;
+
+        let mut messages = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Some(channel) = channel_of(&entry.file_name().to_string_lossy()) else {
+                continue;
+            };
+
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read log file: {}", e))?;
+            messages.extend(content.lines()
+                .filter_map(|line| parse_line(line, username))
+                .map(|(timestamp, text)| LoggedMessage { channel: channel.clone(), timestamp, text }));
+        }
+
+        messages.sort_by_key(|m| m.timestamp);
+        let skip = messages.len().saturating_sub(limit);
+        Ok(messages.into_iter().skip(skip).collect())
+    }
+
+    /// Removes the last `count` messages from a channel's log, reaching into
+    /// its rotations newest first, and returns how many were removed.
+    pub fn purge_recent(&self, channel: &str, count: usize) -> Result<usize, String> {
+        let mut purged = 0;
+        for file in self.log_files(channel) {
+            if purged == count {
+                break;
+            }
+            purged += trim_file(&file, count - purged)?;
+        }
+        Ok(purged)
+    }
+
+    /// Deletes a channel's log and all of its rotations, returning how many
+    /// messages they held.
+    pub fn clear(&self, channel: &str) -> Result<usize, String> {
+        let mut cleared = 0;
+        for file in self.log_files(channel) {
+            cleared += fs::read_to_string(&file).map(|content| content.lines().count()).unwrap_or_default();
+            fs::remove_file(&file)
+                .map_err(|e| format!("Failed to delete log file: {}", e))?;
+        }
+        Ok(cleared)
+    }
+
+    /// A channel's existing log files, newest first: the live log, then `.1`, `.2` and so on.
+    fn log_files(&self, channel: &str) -> Vec<PathBuf> {
+        let path = self.log_path(channel);
+        let mut files = vec![path.clone()];
+        files.extend((1..).map(|n| PathBuf::from(format!("{}.{}", path.display(), n)))
+            .take_while(|rotated| rotated.exists()));
+        files.retain(|file| file.exists());
+        files
+    }
+
+    fn log_path(&self, channel: &str) -> PathBuf {
+        self.log_dir.join(format!("{}.log", encode_file_name(channel)))
+    }
+}
+
+/// Removes the last `count` lines from a log file, returning how many were removed.
+fn trim_file(path: &Path, count: usize) -> Result<usize, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read log file: {}", e))?;
+    let lines: Vec<&str> = content.lines().collect();
+    let keep = lines.len().saturating_sub(count);
+
+    let mut remaining = lines[..keep].join("\n");
+    if keep > 0 {
+        remaining.push('\n');
+    }
+
+    // Write to a temp file first so a failed write can't truncate the log
+    let temp_path = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&temp_path, remaining)
+        .map_err(|e| format!("Failed to write log file: {}", e))?;
+    fs::rename(&temp_path, path)
+        .map_err(|e| format!("Failed to replace log file: {}", e))?;
+
+    Ok(lines.len() - keep)
+}
+
+/// The channel a log file belongs to, for `<channel>.log` and its
+/// `<channel>.log.N` rotations.
+fn channel_of(file_name: &str) -> Option<String> {
+    let stem = file_name.strip_suffix(".log")
+        .or_else(|| file_name.rsplit_once(".log.")
+            .filter(|(_, n)| n.parse::<usize>().is_ok())
+            .map(|(stem, _)| stem))?;
+    Some(decode_file_name(stem))
+}
+
+/// Percent-encodes every byte of a channel name other than ASCII letters,
+/// digits, `-` and `_`. Channel names are user-chosen, so this keeps them from
+/// escaping the log directory, and unlike a lossy substitution it never maps
+/// two channels (say `a.b` and `a_b`) to the same file.
+fn encode_file_name(channel: &str) -> String {
+    let mut encoded = String::with_capacity(channel.len());
+    for byte in channel.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Reverses `encode_file_name`, falling back to the raw name for files it
+/// didn't produce.
+fn decode_file_name(file_name: &str) -> String {
+    let mut bytes = Vec::with_capacity(file_name.len());
+    let mut rest = file_name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).unwrap_or_else(|_| file_name.to_string())
+}
+
+/// Parses a `[timestamp] username: message` log line if it was written by `username`.
+fn parse_line(line: &str, username: &str) -> Option<(u64, String)> {
+    let (timestamp, rest) = line.strip_prefix('[')?.split_once("] ")?;
+    let text = rest.strip_prefix(username)?.strip_prefix(": ")?;
+    Some((timestamp.parse().ok()?, text.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_do_not_collide() {
+        let names = ["a.b", "a b", "a_b", "a%2Eb", "../etc"];
+        let encoded: Vec<String> = names.iter().map(|name| encode_file_name(name)).collect();
+        for (i, a) in encoded.iter().enumerate() {
+            assert!(encoded[i + 1..].iter().all(|b| a != b), "{} collides", a);
+        }
+        assert!(encoded.iter().all(|name| !name.contains(['/', '.'])));
+    }
+
+    #[test]
+    fn file_names_round_trip() {
+        for name in ["general", "a.b", "a b", "a_b", "100%", "café"] {
+            assert_eq!(decode_file_name(&encode_file_name(name)), name);
+        }
+    }
+
+    /// A logger over a scratch directory that rotates after every message,
+    /// removed when the test ends.
+    struct TempLogger(MessageLogger);
+
+    impl TempLogger {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("chatserver-logs-{}", uuid::Uuid::new_v4()));
+            TempLogger(MessageLogger::new(true, &dir.to_string_lossy(), 1))
+        }
+    }
+
+    impl Drop for TempLogger {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.log_dir);
+        }
+    }
+
+    #[test]
+    fn channel_of_accepts_rotations_only() {
+        assert_eq!(channel_of("general.log").as_deref(), Some("general"));
+        assert_eq!(channel_of("a%2Eb.log.3").as_deref(), Some("a.b"));
+        assert_eq!(channel_of("general.log.tmp"), None);
+        assert_eq!(channel_of("notes.txt"), None);
+    }
+
+    #[test]
+    fn messages_by_reads_rotated_logs() {
+        let logger = TempLogger::new();
+        for text in ["one", "two", "three"] {
+            logger.0.log_message("general", "alice", text).unwrap();
+        }
+        logger.0.log_message("general", "bob", "other").unwrap();
+        assert!(logger.0.log_dir.join("general.log.4").exists());
+
+        let mut texts: Vec<String> = logger.0.messages_by("alice", 10).unwrap()
+            .into_iter()
+            .map(|message| message.text)
+            .collect();
+        texts.sort();
+        assert_eq!(texts, ["one", "three", "two"]);
+    }
+
+    #[test]
+    fn purge_recent_reaches_into_rotations() {
+        let logger = TempLogger::new();
+        for text in ["one", "two", "three"] {
+            logger.0.log_message("general", "alice", text).unwrap();
+        }
+
+        assert_eq!(logger.0.purge_recent("general", 2).unwrap(), 2);
+        let texts: Vec<String> = logger.0.messages_by("alice", 10).unwrap()
+            .into_iter()
+            .map(|message| message.text)
+            .collect();
+        assert_eq!(texts, ["one"]);
+
+        assert_eq!(logger.0.purge_recent("general", 5).unwrap(), 1);
+        assert_eq!(logger.0.clear("general").unwrap(), 0);
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const MAX_POLL_OPTIONS: usize = 10;
+const MAX_POLLS_PER_CHANNEL: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct Poll {
+    pub id: u32,
+    pub question: String,
+    pub options: Vec<String>,
+    pub creator: String,
+    votes: HashMap<String, usize>,
+    created_at: Instant,
+    closed: bool,
+}
+
+impl Poll {
+    fn new(id: u32, question: String, options: Vec<String>, creator: String) -> Self {
+        Poll {
+            id,
+            question,
+            options,
+            creator,
+            votes: HashMap::new(),
+            created_at: Instant::now(),
+            closed: false,
+        }
+    }
+
+    pub fn is_open(&self, duration: Duration) -> bool {
+        !self.closed && self.created_at.elapsed() < duration
+    }
+
+    pub fn tally(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.options.len()];
+        for &option in self.votes.values() {
+            counts[option] += 1;
+        }
+        counts
+    }
+
+    /// `prefix` is the configured command prefix, used in the voting hint.
+    pub fn format_announcement(&self, prefix: &str) -> String {
+        let mut text = format!("*** {} started poll #{}: {} ***\n", self.creator, self.id, self.question);
+        for (i, option) in self.options.iter().enumerate() {
+            text.push_str(&format!("  {}. {}\n", i + 1, option));
+        }
+        text.push_str(&format!("Vote with {}vote {} <option>\n", prefix, self.id));
+        text
+    }
+
+    pub fn format_results(&self, duration: Duration) -> String {
+        let status = if self.is_open(duration) { "open" } else { "closed" };
+        let mut text = format!("Poll #{} ({}): {}\n", self.id, status, self.question);
+        for (i, (option, count)) in self.options.iter().zip(self.tally()).enumerate() {
+            let noun = if count == 1 { "vote" } else { "votes" };
+            text.push_str(&format!("  {}. {} - {} {}\n", i + 1, option, count, noun));
+        }
+        text
+    }
+}
+
+pub struct PollManager {
+    polls: HashMap<String, Vec<Poll>>,
+    next_id: u32,
+    duration: Duration,
+}
+
+impl PollManager {
+    pub fn new(duration: Duration) -> Self {
+        PollManager {
+            polls: HashMap::new(),
+            next_id: 1,
+            duration,
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn create_poll(&mut self, channel: &str, creator: &str, question: &str, options: Vec<String>) -> Result<&Poll, String> {
+        if question.is_empty() {
+            return Err("Poll question cannot be empty".to_string());
+        }
+
+        if options.len() < 2 {
+            return Err("A poll needs at least 2 options".to_string());
+        }
+
+        if options.len() > MAX_POLL_OPTIONS {
+            return Err(format!("A poll can have at most {} options", MAX_POLL_OPTIONS));
+        }
+
+        if options.iter().any(|o| o.is_empty()) {
+            return Err("Poll options cannot be empty".to_string());
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let polls = self.polls.entry(channel.to_string()).or_default();
+        if polls.len() >= MAX_POLLS_PER_CHANNEL {
+            polls.remove(0);
+        }
+        polls.push(Poll::new(id, question.to_string(), options, creator.to_string()));
+
+        Ok(&polls[polls.len() - 1])
+    }
+
+    /// Records a vote for a 1-based option number, replacing any earlier vote by the same user.
+    pub fn vote(&mut self, channel: &str, poll_id: u32, username: &str, option: usize) -> Result<(), String> {
+        let duration = self.duration;
+        let poll = self.find_poll_mut(channel, poll_id)?;
+
+        if !poll.is_open(duration) {
+            return Err(format!("Poll #{} is closed", poll_id));
+        }
+
+        if option == 0 || option > poll.options.len() {
+            return Err(format!("Option must be between 1 and {}", poll.options.len()));
+        }
+
+        poll.votes.insert(username.to_string(), option - 1);
+        Ok(())
+    }
+
+    pub fn close_poll(&mut self, channel: &str, poll_id: u32, username: &str) -> Result<&Poll, String> {
+        let duration = self.duration;
+        let poll = self.find_poll_mut(channel, poll_id)?;
+
+        if poll.creator != username {
+            return Err("Only the poll creator can close it".to_string());
+        }
+
+        if !poll.is_open(duration) {
+            return Err(format!("Poll #{} is already closed", poll_id));
+        }
+
+        poll.closed = true;
+        Ok(poll)
+    }
+
+    pub fn get_poll(&self, channel: &str, poll_id: u32) -> Option<&Poll> {
+        self.polls.get(channel)?
+            .iter()
+            .find(|p| p.id == poll_id)
+    }
+
+    fn find_poll_mut(&mut self, channel: &str, poll_id: u32) -> Result<&mut Poll, String> {
+        self.polls.get_mut(channel)
+            .and_then(|polls| polls.iter_mut().find(|p| p.id == poll_id))
+            .ok_or_else(|| format!("No poll #{} in this channel", poll_id))
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Default)]
+struct PresenceEntry {
+    sessions: usize,
+    /// Channel name to the number of this user's sessions in it.
+    channels: HashMap<String, usize>,
+    invisible: bool,
+}
+
+/// One user in a presence snapshot.
+#[derive(Debug)]
+pub struct RosterEntry {
+    pub user: String,
+    pub channels: Vec<String>,
+}
+
+/// Who is online and in which channels, built only from the presence events
+/// sent to subscribers. Applying an event and delivering it happen under the
+/// same lock as taking a snapshot for a new subscriber, so the snapshot plus
+/// the events that follow it always add up to the live state.
+#[derive(Debug, Default)]
+pub struct PresenceRoster {
+    users: HashMap<String, PresenceEntry>,
+}
+
+impl PresenceRoster {
+    pub fn new() -> Self {
+        PresenceRoster::default()
+    }
+
+    /// Records a `connect`, `disconnect`, `join` or `leave` event.
+    pub fn apply(&mut self, event: &str, username: &str, channel: Option<&str>, invisible: bool) {
+        let entry = self.users.entry(username.to_string()).or_default();
+        entry.invisible = invisible;
+
+        match (event, channel) {
+            ("connect", _) => entry.sessions += 1,
+            ("disconnect", _) => entry.sessions = entry.sessions.saturating_sub(1),
+            ("join", Some(channel)) => *entry.channels.entry(channel.to_string()).or_default() += 1,
+            ("leave", Some(channel)) => {
+                if let Some(count) = entry.channels.get_mut(channel) {
+                    *count -= 1;
+                    if *count == 0 {
+                        entry.channels.remove(channel);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        if entry.sessions == 0 && entry.channels.is_empty() {
+            self.users.remove(username);
+        }
+    }
+
+    /// Online users sorted by name, leaving out invisible ones unless asked.
+    pub fn snapshot(&self, include_invisible: bool) -> Vec<RosterEntry> {
+        let sorted: BTreeMap<&String, &PresenceEntry> = self.users.iter()
+            .filter(|(_, entry)| entry.sessions > 0 && (include_invisible || !entry.invisible))
+            .collect();
+
+        sorted.into_iter()
+            .map(|(user, entry)| {
+                let mut channels: Vec<String> = entry.channels.keys().cloned().collect();
+                channels.sort();
+                RosterEntry { user: user.clone(), channels }
+            })
+            .collect()
+    }
+}
+
+/// One presence event line, `PRESENCE <event> <user> [channel]`.
+pub fn format_event(event: &str, username: &str, channel: Option<&str>) -> String {
+    match channel {
+        Some(channel) => format!("PRESENCE {} {} {}\n", event, username, channel),
+        None => format!("PRESENCE {} {}\n", event, username),
+    }
+}
+
+/// A snapshot: a `PRESENCE snapshot <count>` header followed by one
+/// `PRESENCE user <user> [channels...]` line per online user.
+pub fn format_snapshot(roster: &[RosterEntry]) -> String {
+    let mut text = format!("PRESENCE snapshot {}\n", roster.len());
+    for entry in roster {
+        text.push_str("PRESENCE user ");
+        text.push_str(&entry.user);
+        for channel in &entry.channels {
+            text.push(' ');
+            text.push_str(channel);
+        }
+        text.push('\n');
+    }
+    text
+}
@@ -1,4 +1,8 @@
+use std::fmt;
 use serde::{Deserialize, Serialize};
+use crate::format::ColorScheme;
+
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 32;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
@@ -9,4 +13,85 @@ impl User {
     pub fn new(name: String) -> Self {
         User { name }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn is_staff(self) -> bool {
+        matches!(self, Role::Moderator | Role::Admin)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::User => "User",
+            Role::Moderator => "Moderator",
+            Role::Admin => "Admin",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserPrefs {
+    pub autojoin: bool,
+    pub saved_channels: Vec<String>,
+    pub saved_active_channel: Option<String>,
+    pub auto_afk: bool,
+    pub markdown: bool,
+    pub color_scheme: ColorScheme,
+    pub emoji: bool,
+    pub dm_receipts: bool,
+    /// Voice sessions start muted; set by `/voicedefaults`.
+    pub default_muted: bool,
+    /// Voice sessions start deafened (and so muted).
+    pub default_deafened: bool,
+    /// Shown instead of the login name in chat lines and rosters.
+    pub display_name: Option<String>,
+}
+
+impl Default for UserPrefs {
+    fn default() -> Self {
+        UserPrefs {
+            autojoin: false,
+            saved_channels: Vec::new(),
+            saved_active_channel: None,
+            auto_afk: true,
+            markdown: false,
+            color_scheme: ColorScheme::Default,
+            emoji: true,
+            dm_receipts: true,
+            default_muted: false,
+            default_deafened: false,
+            display_name: None,
+        }
+    }
+}
+
+/// Display names may use spaces and a few punctuation marks the login name
+/// can't, but no control characters or characters that could pass for
+/// command or mention syntax.
+pub fn validate_display_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Display name cannot be empty".to_string());
+    }
+    if name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+        return Err(format!("Display name cannot be longer than {} characters", MAX_DISPLAY_NAME_LENGTH));
+    }
+    if name != name.trim() || name.contains("  ") {
+        return Err("Display name cannot start or end with spaces or contain repeated spaces".to_string());
+    }
+    if !name.chars().all(|c| c.is_alphanumeric() || " _-.'!?()".contains(c)) {
+        return Err("Display name may only contain letters, digits, spaces and _ - . ' ! ? ( )".to_string());
+    }
+    Ok(())
+}
@@ -1,20 +1,28 @@
 use std::collections::HashMap;
+use std::time::Instant;
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct VoiceSession {
+    pub connection_id: Uuid,
     pub username: String,
     pub channel: String,
     pub is_muted: bool,
     pub is_deafened: bool,
+    /// When the owning connection dropped; the session is kept for a grace
+    /// period so a quick reconnect can resume it.
+    pub disconnected_at: Option<Instant>,
 }
 
 impl VoiceSession {
-    pub fn new(username: String, channel: String) -> Self {
+    pub fn new(connection_id: Uuid, username: String, channel: String) -> Self {
         VoiceSession {
+            connection_id,
             username,
             channel,
             is_muted: false,
             is_deafened: false,
+            disconnected_at: None,
         }
     }
 }
@@ -30,32 +38,72 @@ impl VoiceChannelManager {
         }
     }
 
-    pub fn join_voice_channel(&mut self, username: String, channel: String) {
-        self.sessions.insert(
-            username.clone(),
-            VoiceSession::new(username, channel)
-        );
-    }
+    /// Starts a session for the given connection, replacing any session the
+    /// user had from an earlier connection, muted and deafened as given
+    /// (deafening also mutes). Fails if `channel` already has
+    /// `max_participants` other sessions, counting ones pending a reconnect.
+    pub fn join_voice_channel(&mut self, connection_id: Uuid, username: String, channel: String, max_participants: usize, muted: bool, deafened: bool) -> Result<(), String> {
+        let participants = self.sessions.values()
+            .filter(|s| s.channel == channel && s.username != username)
+            .count();
+        if participants >= max_participants {
+            return Err("Voice channel is full".to_string());
+        }
 
-    pub fn leave_voice_channel(&mut self, username: &str) -> bool {
-        self.sessions.remove(username).is_some()
+        let mut session = VoiceSession::new(connection_id, username.clone(), channel);
+        session.is_deafened = deafened;
+        session.is_muted = muted || deafened;
+        self.sessions.insert(username, session);
+        Ok(())
     }
 
-    pub fn toggle_mute(&mut self, username: &str) -> Option<bool> {
-        self.sessions.get_mut(username).map(|session| {
-            session.is_muted = !session.is_muted;
-            session.is_muted
-        })
+    /// Ends the user's session only if it belongs to `connection_id`, so a
+    /// stale connection cannot tear down a session created after a reconnect.
+    pub fn leave_voice_channel(&mut self, username: &str, connection_id: Uuid) -> bool {
+        if self.sessions.get(username).is_some_and(|s| s.connection_id == connection_id) {
+            self.sessions.remove(username);
+            true
+        } else {
+            false
+        }
     }
 
-    pub fn toggle_deafen(&mut self, username: &str) -> Option<bool> {
-        self.sessions.get_mut(username).map(|session| {
-            session.is_deafened = !session.is_deafened;
-            if session.is_deafened {
-                session.is_muted = true; // Deafening also mutes
+    /// Marks the session of a dropped connection as pending instead of ending
+    /// it, returning whether there was one. `expire_disconnected` removes it
+    /// if the user hasn't resumed it by then.
+    pub fn mark_disconnected(&mut self, username: &str, connection_id: Uuid) -> bool {
+        match self.sessions.get_mut(username) {
+            Some(session) if session.connection_id == connection_id => {
+                session.disconnected_at = Some(Instant::now());
+                true
             }
-            session.is_deafened
-        })
+            _ => false,
+        }
+    }
+
+    /// Hands a pending session over to a new connection, returning its channel.
+    pub fn resume(&mut self, username: &str, connection_id: Uuid) -> Option<String> {
+        let session = self.sessions.get_mut(username)?;
+        session.disconnected_at.take()?;
+        session.connection_id = connection_id;
+        Some(session.channel.clone())
+    }
+
+    /// Removes the session if it is still pending from `connection_id`.
+    pub fn expire_disconnected(&mut self, username: &str, connection_id: Uuid) -> bool {
+        let pending = self.sessions.get(username)
+            .is_some_and(|s| s.connection_id == connection_id && s.disconnected_at.is_some());
+        if pending {
+            self.sessions.remove(username);
+        }
+        pending
+    }
+
+    /// Ends every session in `channel`, returning the users who were in it.
+    pub fn end_channel_sessions(&mut self, channel: &str) -> Vec<String> {
+        let users = self.get_channel_users(channel);
+        self.sessions.retain(|_, session| session.channel != channel);
+        users
     }
 
     pub fn get_channel_users(&self, channel: &str) -> Vec<String> {
@@ -68,8 +116,4 @@ impl VoiceChannelManager {
     pub fn get_user_session(&self, username: &str) -> Option<&VoiceSession> {
         self.sessions.get(username)
     }
-
-    pub fn list_all_sessions(&self) -> Vec<&VoiceSession> {
-        self.sessions.values().collect()
-    }
 }
\ No newline at end of file
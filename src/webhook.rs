@@ -0,0 +1,119 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEADER_SIZE: usize = 8192;
+const MAX_BODY_SIZE: usize = 16384;
+
+/// Body of the `/summon` webhook.
+#[derive(Debug, Serialize)]
+pub struct SummonPayload {
+    pub user: String,
+    pub by: String,
+    pub server: String,
+}
+
+/// POSTs `payload` as JSON from a background thread so a slow endpoint never
+/// blocks the caller. Failures are only logged.
+pub fn post_json<T: Serialize + Send + 'static>(url: String, payload: T) {
+    thread::spawn(move || {
+        let result = reqwest::blocking::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()
+            .and_then(|client| client.post(&url).json(&payload).send())
+            .and_then(|response| response.error_for_status());
+
+        if let Err(e) = result {
+            eprintln!("Webhook to {} failed: {}", url, e);
+        }
+    });
+}
+
+/// Body of a POST to the incoming webhook endpoint.
+#[derive(Debug, Deserialize)]
+pub struct IncomingMessage {
+    pub channel: String,
+    pub text: String,
+    pub username: Option<String>,
+}
+
+/// The parts of an HTTP request the incoming webhook endpoint looks at.
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub authorization: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Reads one HTTP/1.1 request with bounded header and body sizes.
+/// Errors carry the status line to answer with.
+pub fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, &'static str> {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 1024];
+    let header_end = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if data.len() > MAX_HEADER_SIZE {
+            return Err("431 Request Header Fields Too Large");
+        }
+        let n = stream.read(&mut buffer).map_err(|_| "400 Bad Request")?;
+        if n == 0 {
+            return Err("400 Bad Request");
+        }
+        data.extend_from_slice(&buffer[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Err("400 Bad Request");
+    };
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().map_err(|_| "400 Bad Request")?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.to_string());
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err("413 Payload Too Large");
+    }
+
+    let mut body = data.split_off(header_end + 4);
+    while body.len() < content_length {
+        let n = stream.read(&mut buffer).map_err(|_| "400 Bad Request")?;
+        if n == 0 {
+            return Err("400 Bad Request");
+        }
+        body.extend_from_slice(&buffer[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        authorization,
+        body,
+    })
+}
+
+/// Writes a minimal `Connection: close` HTTP response with a plain-text body.
+pub fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body);
+    let _ = stream.write_all(response.as_bytes());
+}